@@ -0,0 +1,577 @@
+//! This module defines the versioned JSON API, for scripts and bots that want to manage classes and
+//! tickets without scraping the HTML views.
+//!
+//! This module is used to define the following endpoints:
+//!   * *POST*   `/api/v1/classes`                                  ([`create_class`])
+//!   * *GET*    `/api/v1/classes/{id}`                             ([`get_class`])
+//!   * *DELETE* `/api/v1/classes/{id}`                             ([`close_class`])
+//!   * *GET*    `/api/v1/classes/{id}/tickets`                     ([`list_tickets`])
+//!   * *POST*   `/api/v1/classes/{id}/tickets`                     ([`create_ticket`])
+//!   * *GET*    `/api/v1/classes/{id}/tickets/{ticket}`            ([`get_ticket`])
+//!   * *POST*   `/api/v1/classes/{id}/tickets/{ticket}/{action}`   ([`update_ticket`])
+//!   * *POST*   `/api/v1/classes/{id}/tickets/{ticket}/notes`      ([`add_note`])
+//!   * *POST*   `/api/v1/classes/{id}/tickets/{ticket}/messages`   ([`post_message`])
+//!
+//! Endpoints that act on behalf of the teacher require the token of a member of the class's staff, given
+//! either in an `Authorization: Bearer <token>` header or the teacher's cookie. Closing the class requires
+//! the owner's token, and teaching assistants can't dismiss tickets. `action` is one of `claim`, `start`,
+//! `resolve`, `dismiss`, `bump` or `unbump`, and resolving or dismissing a ticket may be given a body of
+//! the form `{ "summary": "..." }`. Tickets returned to staff include their private notes and summaries.
+//! Messages may be posted to a ticket's thread by either a member of staff or the student that opened it,
+//! using the token returned when the ticket was opened.
+//!
+//! Errors are returned as a JSON object of the form `{ "error": "unknown_class", "message": "..." }`, with
+//! an appropriate HTTP status code.
+
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{self, Forbidden, Token};
+use crate::queue::Policy;
+use crate::ratelimit::ClientIp;
+use crate::staff::Permission;
+use crate::state::{AppState, ClassCode, ClassesFull, LookupError, UnknownClass};
+use crate::student;
+use crate::teacher::Action;
+use crate::ticket::{Actor, Duplicates, Status, Ticket, TicketError, TicketId};
+
+/// Error type for failures in the JSON API
+#[derive(thiserror::Error, Debug)]
+pub enum ApiError {
+    #[error(transparent)]
+    UnknownClass(#[from] UnknownClass),
+    #[error(transparent)]
+    ClassesFull(#[from] ClassesFull),
+    #[error(transparent)]
+    Forbidden(#[from] Forbidden),
+    #[error(transparent)]
+    Ticket(#[from] TicketError),
+    /// Too many unknown class codes have been given by the client
+    #[error(transparent)]
+    RateLimited(LookupError),
+    /// The request body, path or query was malformed
+    #[error("{0}")]
+    BadRequest(String),
+}
+
+impl From<LookupError> for ApiError {
+    fn from(e: LookupError) -> ApiError {
+        match e {
+            LookupError::UnknownClass(e) => ApiError::UnknownClass(e),
+            e @ LookupError::RateLimited => ApiError::RateLimited(e),
+        }
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(e: JsonRejection) -> ApiError {
+        ApiError::BadRequest(e.body_text())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(e: PathRejection) -> ApiError {
+        ApiError::BadRequest(e.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(e: QueryRejection) -> ApiError {
+        ApiError::BadRequest(e.body_text())
+    }
+}
+
+/// The JSON body of an error response
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    /// Machine-readable kind of error
+    error: &'static str,
+    /// Human-readable description of the error
+    message: String,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, error) = match &self {
+            ApiError::UnknownClass(_) | ApiError::Ticket(TicketError::UnknownClass(_)) => {
+                (StatusCode::NOT_FOUND, "unknown_class")
+            }
+            ApiError::ClassesFull(_) => (StatusCode::SERVICE_UNAVAILABLE, "classes_full"),
+            ApiError::Forbidden(_) => (StatusCode::FORBIDDEN, "forbidden"),
+            ApiError::Ticket(TicketError::UnknownTicket(_)) => {
+                (StatusCode::NOT_FOUND, "unknown_ticket")
+            }
+            ApiError::Ticket(TicketError::Closed(_)) => (StatusCode::CONFLICT, "ticket_closed"),
+            ApiError::Ticket(TicketError::InvalidTransition { .. }) => {
+                (StatusCode::CONFLICT, "invalid_transition")
+            }
+            ApiError::Ticket(TicketError::CategoryRequired | TicketError::UnknownCategory(_)) => {
+                (StatusCode::BAD_REQUEST, "invalid_category")
+            }
+            ApiError::Ticket(TicketError::Duplicate(_)) => {
+                (StatusCode::CONFLICT, "duplicate_ticket")
+            }
+            ApiError::Ticket(TicketError::EmptyNote) => (StatusCode::BAD_REQUEST, "empty_note"),
+            ApiError::Ticket(TicketError::EmptyMessage) => {
+                (StatusCode::BAD_REQUEST, "empty_message")
+            }
+            ApiError::Ticket(TicketError::TooManyMessages(_)) => {
+                (StatusCode::CONFLICT, "too_many_messages")
+            }
+            ApiError::RateLimited(_) => (StatusCode::TOO_MANY_REQUESTS, "rate_limited"),
+            ApiError::BadRequest(_) => (StatusCode::BAD_REQUEST, "bad_request"),
+        };
+
+        let body = ErrorBody {
+            error,
+            message: self.to_string(),
+        };
+
+        (status, Json(body)).into_response()
+    }
+}
+
+/// A ticket, along with its current status
+#[derive(Debug, Serialize)]
+pub struct TicketInfo {
+    #[serde(flatten)]
+    ticket: Ticket,
+    /// The ticket's current status
+    status: Status,
+    /// The number of messages from the student that staff haven't read
+    unread: usize,
+}
+
+impl From<Ticket> for TicketInfo {
+    fn from(ticket: Ticket) -> TicketInfo {
+        let status = ticket.status();
+        let unread = ticket.unread();
+        TicketInfo {
+            ticket,
+            status,
+            unread,
+        }
+    }
+}
+
+/// Summary of a class
+#[derive(Debug, Serialize)]
+pub struct ClassInfo {
+    /// The code students use to join the class, also used in URLs
+    code: ClassCode,
+    /// The number of tickets that are still open
+    open_tickets: usize,
+    /// The total number of tickets opened in the class
+    total_tickets: usize,
+    /// The categories students choose from when opening a ticket
+    categories: Vec<String>,
+    /// The policy deciding the order tickets are helped in
+    policy: Policy,
+    /// What happens when a student opens a ticket while they already have one open
+    duplicates: Duplicates,
+}
+
+impl ClassInfo {
+    /// Summarise a given class
+    fn new(state: &AppState, code: ClassCode) -> Result<ClassInfo, UnknownClass> {
+        let (open_tickets, total_tickets, categories, policy, duplicates) =
+            state.with_tickets(code, |t| {
+                let open = t.tickets().filter(|t| t.status().is_open()).count();
+                (
+                    open,
+                    t.len(),
+                    t.categories().to_vec(),
+                    t.policy(),
+                    t.duplicates(),
+                )
+            })?;
+
+        Ok(ClassInfo {
+            code,
+            open_tickets,
+            total_tickets,
+            categories,
+            policy,
+            duplicates,
+        })
+    }
+}
+
+/// Response to creating a new class
+#[derive(Debug, Serialize)]
+pub struct CreatedClass {
+    #[serde(flatten)]
+    class: ClassInfo,
+    /// The token required for teacher-only endpoints
+    teacher_token: Token,
+}
+
+/// The (optional) body of a request to create a class
+#[derive(Debug, Default, Deserialize)]
+pub struct NewClass {
+    /// The categories students choose from when opening a ticket
+    #[serde(default)]
+    categories: Vec<String>,
+}
+
+/// Create a new class, optionally with a list of `categories`. The response contains the teacher's token,
+/// which must be kept secret.
+pub async fn create_class(
+    State(state): State<AppState>,
+    body: Result<Json<NewClass>, JsonRejection>,
+) -> Result<(StatusCode, Json<CreatedClass>), ApiError> {
+    let body = match body {
+        Ok(Json(body)) => body,
+        // the body is optional
+        Err(JsonRejection::MissingJsonContentType(_)) => NewClass::default(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let code = state.create_class()?;
+    state.with_tickets_mut(code, |t| t.set_categories(&body.categories))?;
+    let teacher_token = state.with_tickets(code, |t| t.teacher_token().clone())?;
+
+    let created = CreatedClass {
+        class: ClassInfo::new(&state, code)?,
+        teacher_token,
+    };
+
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+/// Retrieve a summary of a class. Requires the teacher's token.
+pub async fn get_class(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    id: Result<Path<String>, PathRejection>,
+    headers: HeaderMap,
+) -> Result<Json<ClassInfo>, ApiError> {
+    let Path(id) = id?;
+    let code = state.lookup_code(ip, &id)?;
+
+    auth::require_teacher(&state, code, &headers)?;
+
+    Ok(Json(ClassInfo::new(&state, code)?))
+}
+
+/// Close a class, removing all of its tickets. Requires the owner's token.
+pub async fn close_class(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    id: Result<Path<String>, PathRejection>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    let Path(id) = id?;
+    let code = state.lookup_code(ip, &id)?;
+
+    auth::require_teacher(&state, code, &headers)?.require(Permission::ManageClass)?;
+
+    state.close_class(code)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The URL query arguments to the ticket list
+#[derive(Debug, Deserialize)]
+pub struct ListArgs {
+    /// If `Some(true)`, then include resolved and cancelled tickets
+    all: Option<bool>,
+}
+
+/// List the open tickets in a class, in the order they should be helped in. If `?all=true` is given, every
+/// ticket is included instead, oldest first. Requires the teacher's token.
+pub async fn list_tickets(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    id: Result<Path<String>, PathRejection>,
+    args: Result<Query<ListArgs>, QueryRejection>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<TicketInfo>>, ApiError> {
+    let (Path(id), Query(args)) = (id?, args?);
+    let code = state.lookup_code(ip, &id)?;
+
+    auth::require_teacher(&state, code, &headers)?;
+
+    let all = args.all.unwrap_or(false);
+    let tickets = state.with_tickets(code, |t| {
+        let tickets = if all {
+            t.tickets().collect()
+        } else {
+            t.queue()
+        };
+        tickets.into_iter().cloned().map(TicketInfo::from).collect()
+    })?;
+
+    Ok(Json(tickets))
+}
+
+/// The body of a request to open a ticket
+#[derive(Debug, Deserialize)]
+pub struct NewTicket {
+    /// The name of the student opening the ticket
+    student: String,
+    /// An (optional) brief description of the ticket
+    desc: Option<String>,
+    /// The category of the ticket, required if the class has categories
+    category: Option<String>,
+}
+
+/// Response to opening a new ticket
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum CreatedTicket {
+    /// The ticket was opened, or merged into a ticket the client already owns
+    Opened {
+        #[serde(flatten)]
+        ticket: Box<TicketInfo>,
+        /// The token allowing the student to change the ticket
+        token: Token,
+        /// Whether the ticket was merged into the student's existing open ticket
+        merged: bool,
+    },
+    /// The ticket was merged into an existing ticket that was only recognised by the student's name, so may
+    /// belong to somebody else. Only its ID is given.
+    Merged { id: TicketId, merged: bool },
+}
+
+/// Open a ticket in a class. The response contains the student's token for the ticket. If the student
+/// (recognised by name) already has an open ticket, the ticket is either refused or merged into it,
+/// depending on the class's settings, in which case only the existing ticket's ID is returned.
+pub async fn create_ticket(
+    State(state): State<AppState>,
+    id: Result<Path<String>, PathRejection>,
+    ClientIp(ip): ClientIp,
+    body: Result<Json<NewTicket>, JsonRejection>,
+) -> Result<(StatusCode, Json<CreatedTicket>), ApiError> {
+    let (Path(id), Json(body)) = (id?, body?);
+    // anyone may open a ticket, so unknown codes count towards the client's rate limit
+    let code = state.lookup_code(ip, &id)?;
+
+    if body.student.trim().is_empty() {
+        return Err(ApiError::BadRequest(
+            "student name must not be empty".into(),
+        ));
+    }
+
+    // treat a whitespace-only description as no description
+    let desc = body.desc.filter(|d| !d.trim().is_empty());
+    let opened = student::open_ticket(&state, code, &body.student, desc, body.category, None)?;
+
+    let status = if opened.merged {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    };
+    let created = match opened.token {
+        Some(token) => CreatedTicket::Opened {
+            // a ticket the student owns still mustn't leak the staff's notes
+            ticket: Box::new(opened.ticket.for_student().into()),
+            token,
+            merged: opened.merged,
+        },
+        // the ticket's description and thread may belong to a classmate with the same name
+        None => CreatedTicket::Merged {
+            id: opened.ticket.id(),
+            merged: true,
+        },
+    };
+
+    Ok((status, Json(created)))
+}
+
+/// Retrieve a single ticket. Requires the teacher's token.
+pub async fn get_ticket(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    path: Result<Path<(String, TicketId)>, PathRejection>,
+    headers: HeaderMap,
+) -> Result<Json<TicketInfo>, ApiError> {
+    let Path((id, ticket)) = path?;
+    let code = state.lookup_code(ip, &id)?;
+
+    auth::require_teacher(&state, code, &headers)?;
+
+    let ticket = state
+        .with_tickets(code, |t| t.get(ticket).cloned())?
+        .ok_or(TicketError::UnknownTicket(ticket))?;
+
+    Ok(Json(ticket.into()))
+}
+
+/// The (optional) body of a request to perform an action on a ticket
+#[derive(Debug, Default, Deserialize)]
+pub struct ActionBody {
+    /// What the problem turned out to be, when resolving or dismissing the ticket
+    summary: Option<String>,
+}
+
+/// Perform an action (claim, start, resolve, dismiss, bump or unbump) on a ticket. Requires the teacher's
+/// token.
+pub async fn update_ticket(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    path: Result<Path<(String, TicketId, Action)>, PathRejection>,
+    headers: HeaderMap,
+    body: Result<Json<ActionBody>, JsonRejection>,
+) -> Result<Json<TicketInfo>, ApiError> {
+    let Path((id, ticket, action)) = path?;
+    let body = match body {
+        Ok(Json(body)) => body,
+        // the body is optional
+        Err(JsonRejection::MissingJsonContentType(_)) => ActionBody::default(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let code = state.lookup_code(ip, &id)?;
+
+    let member = auth::require_teacher(&state, code, &headers)?;
+    member.require(action.permission())?;
+
+    let ticket = action.perform(&state, code, ticket, &member, body.summary.as_deref())?;
+
+    Ok(Json(ticket.into()))
+}
+
+/// The body of a request to add a note to a ticket
+#[derive(Debug, Deserialize)]
+pub struct NewNote {
+    /// The contents of the note
+    text: String,
+}
+
+/// Add a private note to a ticket. Requires the teacher's token.
+pub async fn add_note(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    path: Result<Path<(String, TicketId)>, PathRejection>,
+    headers: HeaderMap,
+    body: Result<Json<NewNote>, JsonRejection>,
+) -> Result<Json<TicketInfo>, ApiError> {
+    let (Path((id, ticket)), Json(body)) = (path?, body?);
+    let code = state.lookup_code(ip, &id)?;
+
+    let member = auth::require_teacher(&state, code, &headers)?;
+    member.require(Permission::ManageTickets)?;
+
+    let ticket = state.add_note(code, ticket, Actor::staff(&member), &body.text)?;
+
+    Ok(Json(ticket.into()))
+}
+
+/// The body of a request to post a message to a ticket's thread
+#[derive(Debug, Deserialize)]
+pub struct NewMessage {
+    /// The contents of the message
+    text: String,
+}
+
+/// Post a message to a ticket's thread. Requires either the teacher's token, or the student's token for the
+/// ticket.
+pub async fn post_message(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    path: Result<Path<(String, TicketId)>, PathRejection>,
+    headers: HeaderMap,
+    body: Result<Json<NewMessage>, JsonRejection>,
+) -> Result<Json<TicketInfo>, ApiError> {
+    let (Path((id, ticket)), Json(body)) = (path?, body?);
+    let code = state.lookup_code(ip, &id)?;
+
+    let ticket = match auth::require_teacher(&state, code, &headers) {
+        Ok(member) => {
+            member.require(Permission::ManageTickets)?;
+            state.post_message(code, ticket, Actor::staff(&member), &body.text)?
+        }
+        Err(_) => {
+            auth::require_owner(&state, code, ticket, &headers)?;
+            // students mustn't see the staff's private notes
+            state
+                .post_message(code, ticket, Actor::Student, &body.text)?
+                .for_student()
+        }
+    };
+
+    Ok(Json(ticket.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::body::HttpBody;
+
+    async fn error(e: ApiError) -> (StatusCode, serde_json::Value) {
+        let mut response = e.into_response();
+        let status = response.status();
+        let body = response.body_mut().data().await.unwrap().unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn errors_have_status_and_message() {
+        let id: TicketId = serde_json::from_str("3").unwrap();
+        let code = ClassCode::parse("ABCDEF").unwrap();
+
+        let cases = [
+            (
+                ApiError::ClassesFull(ClassesFull),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "classes_full",
+            ),
+            (
+                Forbidden::NotTeacher(code).into(),
+                StatusCode::FORBIDDEN,
+                "forbidden",
+            ),
+            (
+                TicketError::UnknownTicket(id).into(),
+                StatusCode::NOT_FOUND,
+                "unknown_ticket",
+            ),
+            (
+                TicketError::Closed(id).into(),
+                StatusCode::CONFLICT,
+                "ticket_closed",
+            ),
+            (
+                TicketError::CategoryRequired.into(),
+                StatusCode::BAD_REQUEST,
+                "invalid_category",
+            ),
+            (
+                TicketError::Duplicate(id).into(),
+                StatusCode::CONFLICT,
+                "duplicate_ticket",
+            ),
+            (
+                TicketError::EmptyNote.into(),
+                StatusCode::BAD_REQUEST,
+                "empty_note",
+            ),
+            (
+                LookupError::RateLimited.into(),
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+            ),
+            (
+                ApiError::BadRequest("bad".into()),
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+            ),
+        ];
+
+        for (e, status, kind) in cases {
+            let message = e.to_string();
+            let (actual, body) = error(e).await;
+            assert_eq!(actual, status, "{kind}");
+            assert_eq!(body["error"], kind);
+            assert_eq!(body["message"], message.as_str());
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! This module serves the static files (JS and stylesheets) in `static/`, which are compiled into the
+//! executable so that it can be run from any directory. It defines the following endpoints:
+//!
+//! * GET `/static/:file` - a static file, by its plain name (e.g. `style.css`) or content-hashed name (e.g.
+//!   `style.1a2b3c4d5e6f7a8b.css`)
+//!
+//! Pages link to files by their hashed name (see [`path`]), which changes whenever the file does, so those
+//! responses can be cached forever. Plain names are still served for URLs that must not change, such as the
+//! service worker's, but clients must revalidate them using their ETag.
+//!
+//! For development, files may be served from a directory instead (see `static_dir` in [`Config`]), so that
+//! changes are picked up without rebuilding. Those responses are never cached.
+//!
+//! [`Config`]: crate::config::Config
+
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+
+use axum::extract::{Path, State};
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, MethodRouter};
+
+use sha2::{Digest, Sha256};
+
+/// Produces an embedded file's name and contents, relative to the repository's `static/` directory
+macro_rules! embed {
+    ($name:literal) => {
+        (
+            $name,
+            include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/../static/", $name)),
+        )
+    };
+}
+
+/// Every file compiled into the executable
+const FILES: [(&str, &[u8]); 5] = [
+    embed!("service-worker.js"),
+    embed!("style.css"),
+    embed!("teacher-view.js"),
+    embed!("terminal.min.css"),
+    embed!("ticket-view.js"),
+];
+
+/// Cache headers for a hashed file, which will never change
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// Cache headers for a file that may change, so must be revalidated before each use
+const REVALIDATE: &str = "no-cache";
+
+/// A file compiled into the executable
+struct Asset {
+    /// The file's name, e.g. `style.css`
+    name: &'static str,
+    /// The file's name with its hash inserted before the extension, e.g. `style.1a2b3c4d5e6f7a8b.css`
+    hashed: String,
+    /// A quoted hash of the file's contents, for use as an ETag
+    etag: String,
+    /// The file's contents
+    data: &'static [u8],
+}
+
+/// Returns the embedded files, hashing them the first time it is called
+fn assets() -> &'static [Asset] {
+    static ASSETS: OnceLock<Vec<Asset>> = OnceLock::new();
+
+    ASSETS.get_or_init(|| {
+        FILES
+            .iter()
+            .map(|&(name, data)| {
+                let hash: String = Sha256::digest(data)
+                    .iter()
+                    .take(8)
+                    .map(|b| format!("{b:02x}"))
+                    .collect();
+
+                let hashed = match name.rsplit_once('.') {
+                    Some((stem, ext)) => format!("{stem}.{hash}.{ext}"),
+                    None => format!("{name}.{hash}"),
+                };
+
+                Asset {
+                    name,
+                    hashed,
+                    etag: format!("\"{hash}\""),
+                    data,
+                }
+            })
+            .collect()
+    })
+}
+
+/// Returns the path of a static file (e.g. `/static/style.1a2b3c4d5e6f7a8b.css`), relative to the root of the
+/// application. Files that aren't embedded are linked to by their plain name.
+pub fn path(name: &str) -> String {
+    match assets().iter().find(|a| a.name == name) {
+        Some(asset) => format!("/static/{}", asset.hashed),
+        None => format!("/static/{name}"),
+    }
+}
+
+/// Returns the handler serving static files, from the given directory if one is given
+pub fn route<S>(dir: Option<PathBuf>) -> MethodRouter<S> {
+    get(serve).with_state(Arc::new(dir))
+}
+
+/// The `Content-Type` of a file, based on its extension
+fn content_type(name: &str) -> String {
+    let mime = mime_guess::from_path(name).first_or_octet_stream();
+
+    // text files are all stored as UTF-8
+    if mime.type_() == mime_guess::mime::TEXT || mime.subtype() == mime_guess::mime::JAVASCRIPT {
+        format!("{mime}; charset=utf-8")
+    } else {
+        mime.to_string()
+    }
+}
+
+/// Handler for serving a static file
+async fn serve(
+    State(dir): State<Arc<Option<PathBuf>>>,
+    Path(file): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let asset = assets().iter().find(|a| a.hashed == file || a.name == file);
+
+    if let Some(dir) = dir.as_ref() {
+        // links use hashed names, but the directory only contains plain names
+        let name = asset.map(|a| a.name).unwrap_or(&file);
+
+        // only serve files directly inside the directory
+        if name.contains(['/', '\\']) || name.starts_with('.') {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+
+        return match tokio::fs::read(dir.join(name)).await {
+            Ok(data) => (
+                [
+                    (CONTENT_TYPE, content_type(name)),
+                    (CACHE_CONTROL, REVALIDATE.to_string()),
+                ],
+                data,
+            )
+                .into_response(),
+            Err(_) => StatusCode::NOT_FOUND.into_response(),
+        };
+    }
+
+    let Some(asset) = asset else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let cache = if asset.hashed == file {
+        IMMUTABLE
+    } else {
+        REVALIDATE
+    };
+    let headers_out = [
+        (CONTENT_TYPE, content_type(asset.name)),
+        (CACHE_CONTROL, cache.to_string()),
+        (ETAG, asset.etag.clone()),
+    ];
+
+    // the client already has this version of the file
+    let cached = headers
+        .get(IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|tags| {
+            tags.split(',')
+                .any(|t| t.trim() == asset.etag || t.trim() == "*")
+        });
+
+    if cached {
+        (StatusCode::NOT_MODIFIED, headers_out).into_response()
+    } else {
+        (headers_out, asset.data).into_response()
+    }
+}
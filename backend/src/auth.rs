@@ -0,0 +1,303 @@
+//! This module contains the logic for authenticating teachers. No endpoints are defined in this module.
+//!
+//! When a class is created, it is given a random secret [`Token`] identifying its owner, and each member of
+//! staff invited later is given their own (see [`crate::staff`]). The teacher's browser is handed their token
+//! in a cookie scoped to the class, and can share it with their other devices via their admin link
+//! (`/class/{id}/admin?token=...`). Teacher-only handlers call [`require_teacher`] to check the cookie and
+//! find out which member of staff made the request, producing a [`Forbidden`] error if it is missing or
+//! incorrect. Scripts using the JSON API may instead give the token in an `Authorization: Bearer <token>`
+//! header.
+//!
+//! Similarly, each ticket is given a token when it is opened, which is stored in a cookie scoped to the
+//! ticket's status page. This lets the student that opened the ticket edit or withdraw it (see
+//! [`require_owner`]). Only that student and the class's staff may follow the ticket's status (see
+//! [`require_viewer`]), as it shows the student's description of their problem.
+//!
+//! Students are also given a session cookie scoped to the class, the first time they open a ticket in it.
+//! This isn't used for authentication, only to recognise a student opening more than one ticket (see
+//! [`get_session`]).
+
+use std::fmt;
+
+use axum::http::header::{AUTHORIZATION, COOKIE, SET_COOKIE};
+use axum::http::{HeaderMap, HeaderName, StatusCode};
+use axum::response::{IntoResponse, Response};
+
+use base64ct::{Base64UrlUnpadded, Encoding};
+use serde::{Deserialize, Serialize};
+
+use crate::staff::{Permission, Role, Staff};
+use crate::state::{AppState, ClassCode};
+use crate::ticket::TicketId;
+use crate::ui;
+
+/// Name of the cookie containing the teacher's token
+const TEACHER_COOKIE: &str = "teacher_token";
+
+/// Name of the cookie containing the token of the ticket a student opened
+const TICKET_COOKIE: &str = "ticket_token";
+
+/// Name of the cookie identifying the student's browser within a class
+const SESSION_COOKIE: &str = "student_session";
+
+/// The longest session we accept from a cookie, so that students can't fill the class's storage
+const MAX_SESSION_LEN: usize = 64;
+
+/// An unguessable secret, used to prove that a user is allowed to perform an action
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Token(String);
+
+impl Token {
+    /// Generate a new random token, containing 128 bits of entropy
+    pub fn generate() -> Token {
+        let bytes: [u8; 16] = rand::random();
+        Token(Base64UrlUnpadded::encode_string(&bytes))
+    }
+
+    /// Compare the token against a string given by the user, in constant time to avoid leaking how
+    /// much of the token was correct
+    pub fn matches(&self, given: &str) -> bool {
+        secret_eq(&self.0, given)
+    }
+}
+
+/// Compare a secret against a string given by the user, in constant time to avoid leaking how much of the
+/// secret was correct. Only the length of the secret may be leaked.
+pub fn secret_eq(secret: &str, given: &str) -> bool {
+    let (a, b) = (secret.as_bytes(), given.as_bytes());
+
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.0)
+    }
+}
+
+/// Error produced when a user attempts to access a page they are not authorised to access
+#[derive(thiserror::Error, Debug)]
+pub enum Forbidden {
+    /// The user is not the teacher of the class
+    #[error("You are not the teacher of class {0}")]
+    NotTeacher(ClassCode),
+    /// The user is a member of staff, but their role doesn't allow the action
+    #[error("As a {0}, you can't {1}")]
+    Restricted(Role, Permission),
+    /// The user did not open the ticket
+    #[error("You did not open ticket {0}")]
+    NotOwner(TicketId),
+}
+
+impl IntoResponse for Forbidden {
+    fn into_response(self) -> Response {
+        let hint = match self {
+            Forbidden::NotTeacher(_) => {
+                "Ask the teacher for the class's admin link to gain access."
+            }
+            Forbidden::Restricted(..) => "Ask the class's owner to do this for you.",
+            Forbidden::NotOwner(_) => {
+                "Only the student that opened a ticket can view or change it."
+            }
+        };
+
+        let page = ui::base(
+            "Forbidden",
+            maud::html! {
+                p { (self) "." }
+                p { (hint) }
+                a href=(ui::url("/")) { "Go back." }
+            },
+        );
+
+        (StatusCode::FORBIDDEN, page).into_response()
+    }
+}
+
+/// Retrieve the value of a cookie from a request's headers
+pub fn get_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        // ignore any headers that aren't valid strings
+        .filter_map(|h| h.to_str().ok())
+        // each header contains a `;`-separated list of `name=value` pairs
+        .flat_map(|h| h.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+/// Retrieve the token from a request's `Authorization: Bearer <token>` header
+pub fn get_bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Produce a `Set-Cookie` header that stores the teacher's token for a class
+pub fn teacher_cookie(code: ClassCode, token: &Token) -> [(HeaderName, String); 1] {
+    let path = ui::url(format!("/class/{code}"));
+
+    [(
+        SET_COOKIE,
+        format!("{TEACHER_COOKIE}={token}; Path={path}; HttpOnly; SameSite=Lax"),
+    )]
+}
+
+/// Produce a `Set-Cookie` header that stores the student's token for a ticket
+pub fn ticket_cookie(code: ClassCode, id: TicketId, token: &Token) -> [(HeaderName, String); 1] {
+    let path = ui::url(format!("/class/{code}/ticket/{}", id.as_usize()));
+
+    [(
+        SET_COOKIE,
+        format!("{TICKET_COOKIE}={token}; Path={path}; HttpOnly; SameSite=Lax"),
+    )]
+}
+
+/// Produce a `Set-Cookie` header that identifies the student's browser within a class
+pub fn session_cookie(code: ClassCode, session: &str) -> [(HeaderName, String); 1] {
+    let path = ui::url(format!("/class/{code}"));
+
+    [(
+        SET_COOKIE,
+        format!("{SESSION_COOKIE}={session}; Path={path}; HttpOnly; SameSite=Lax"),
+    )]
+}
+
+/// Retrieve the student's session from a request's cookies, if they have a valid one
+pub fn get_session(headers: &HeaderMap) -> Option<&str> {
+    get_cookie(headers, SESSION_COOKIE).filter(|s| !s.is_empty() && s.len() <= MAX_SESSION_LEN)
+}
+
+/// Check that the request comes from a member of staff (the owner, a co-teacher or a teaching assistant)
+/// of a given class, returning the member. Handlers should then check the member's permissions with
+/// [`Staff::require`].
+pub fn require_teacher(
+    state: &AppState,
+    code: ClassCode,
+    headers: &HeaderMap,
+) -> Result<Staff, Forbidden> {
+    let given = get_bearer(headers)
+        .or_else(|| get_cookie(headers, TEACHER_COOKIE))
+        .ok_or(Forbidden::NotTeacher(code))?;
+
+    // a class that has just been closed has no staff
+    state
+        .with_tickets(code, |t| t.member(given))
+        .ok()
+        .flatten()
+        .ok_or(Forbidden::NotTeacher(code))
+}
+
+/// Check that the request comes from the student that opened a given ticket
+pub fn require_owner(
+    state: &AppState,
+    code: ClassCode,
+    id: TicketId,
+    headers: &HeaderMap,
+) -> Result<(), Forbidden> {
+    let given = get_bearer(headers)
+        .or_else(|| get_cookie(headers, TICKET_COOKIE))
+        .ok_or(Forbidden::NotOwner(id))?;
+
+    let matches = state.with_tickets(code, |t| {
+        t.student_token(id)
+            .map(|token| token.matches(given))
+            .unwrap_or(false)
+    });
+
+    if matches.unwrap_or(false) {
+        Ok(())
+    } else {
+        Err(Forbidden::NotOwner(id))
+    }
+}
+
+/// Check that the request comes from the student that opened a given ticket, or from a member of the class's
+/// staff
+pub fn require_viewer(
+    state: &AppState,
+    code: ClassCode,
+    id: TicketId,
+    headers: &HeaderMap,
+) -> Result<(), Forbidden> {
+    match require_owner(state, code, id, headers) {
+        Ok(()) => Ok(()),
+        Err(e) => require_teacher(state, code, headers)
+            .map(|_| ())
+            .map_err(|_| e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(name, value.parse().unwrap());
+        }
+
+        headers
+    }
+
+    #[test]
+    fn secrets_are_compared_exactly() {
+        assert!(secret_eq("s3cret", "s3cret"));
+        assert!(!secret_eq("s3cret", "s3creT"));
+        // a prefix or extension of the secret doesn't match
+        assert!(!secret_eq("s3cret", "s3cre"));
+        assert!(!secret_eq("s3cret", "s3crets"));
+        assert!(!secret_eq("s3cret", ""));
+        assert!(secret_eq("", ""));
+
+        let token = Token::generate();
+        assert!(token.matches(&token.to_string()));
+        assert!(!token.matches(&Token::generate().to_string()));
+    }
+
+    #[test]
+    fn cookies_are_found_by_name() {
+        let headers = headers(&[
+            (COOKIE, "session=abc; teacher_token=t0k=n"),
+            (COOKIE, "ticket_token=xyz"),
+        ]);
+
+        assert_eq!(get_cookie(&headers, "session"), Some("abc"));
+        // values may contain `=`
+        assert_eq!(get_cookie(&headers, TEACHER_COOKIE), Some("t0k=n"));
+        assert_eq!(get_cookie(&headers, TICKET_COOKIE), Some("xyz"));
+        // names must match exactly
+        assert_eq!(get_cookie(&headers, "ticket"), None);
+        assert_eq!(get_cookie(&HeaderMap::new(), "session"), None);
+    }
+
+    #[test]
+    fn bearer_tokens_are_read() {
+        assert_eq!(
+            get_bearer(&headers(&[(AUTHORIZATION, "Bearer abc ")])),
+            Some("abc")
+        );
+        assert_eq!(get_bearer(&headers(&[(AUTHORIZATION, "Basic abc")])), None);
+        assert_eq!(get_bearer(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn sessions_are_limited_in_length() {
+        let long = format!("{SESSION_COOKIE}={}", "a".repeat(MAX_SESSION_LEN + 1));
+
+        assert_eq!(
+            get_session(&headers(&[(COOKIE, &format!("{SESSION_COOKIE}=abc"))])),
+            Some("abc")
+        );
+        assert_eq!(
+            get_session(&headers(&[(COOKIE, &format!("{SESSION_COOKIE}="))])),
+            None
+        );
+        assert_eq!(get_session(&headers(&[(COOKIE, &long)])), None);
+    }
+}
@@ -1,33 +1,153 @@
 //! This module contains the interface for creating and joining classes.
 //!
 //! This module is used to define the following endpoints:
-//!   * *GET*  `/create-class` ([`create`])
-//!   * *GET*  `/join-class`   ([`join_form`])
-//!   * *POST* `/join-class`   ([`join_submit`])
+//!   * *GET*  `/create-class`           ([`create_form`])
+//!   * *POST* `/create-class`           ([`create`])
+//!   * *GET*  `/join-class`             ([`join_form`])
+//!   * *POST* `/join-class`             ([`join_submit`])
+//!   * *GET*  `/class/{id}/admin`       ([`admin`])
+//!   * *POST* `/class/{id}/register`    ([`register`])
+//!   * *POST* `/class/{id}/unsubscribe` ([`unsubscribe`])
 
-use axum::extract::{Form, Path, State};
-use axum::response::Redirect;
+use axum::extract::{Form, Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Redirect, Response};
 use axum::Json;
 
 use serde::Deserialize;
 use web_push_native::WebPushBuilder;
 
-use crate::state::AppState;
+use crate::auth::{self, Forbidden};
+use crate::push::Subscriber;
+use crate::ratelimit::ClientIp;
+use crate::state::{AppState, LookupError, UnknownClass, MAX_CODE_LEN};
 use crate::ui;
 
-/// Create a new clasroom, and redirect user to the teacher's view of the classroom
-pub async fn create(State(state): State<AppState>) -> Redirect {
-    let id = state.create_class().as_u16();
+/// Error type for pages within a class, which can't be shown if the class doesn't exist or the user isn't
+/// allowed to see it
+#[derive(thiserror::Error, Debug)]
+pub enum ClassError {
+    #[error(transparent)]
+    Lookup(#[from] LookupError),
+    #[error(transparent)]
+    Forbidden(#[from] Forbidden),
+}
+
+impl From<UnknownClass> for ClassError {
+    fn from(e: UnknownClass) -> ClassError {
+        ClassError::Lookup(e.into())
+    }
+}
+
+impl IntoResponse for ClassError {
+    fn into_response(self) -> Response {
+        match self {
+            ClassError::Lookup(e) => lookup_error(e),
+            ClassError::Forbidden(e) => e.into_response(),
+        }
+    }
+}
+
+/// The form presented to the user to create a classroom
+pub async fn create_form() -> maud::Markup {
+    ui::base(
+        "Create Class",
+        maud::html! {
+            form class="t-form" action=(ui::url("/create-class")) method="post" {
+                fieldset {
+                    legend { "Class" }
+
+                    div class="form-group" {
+                        label for="categories" { "Categories (one per line, optional): " }
+                        textarea name="categories" rows="5"
+                            placeholder="e.g.\nCan't log in\nExercise 3" {}
+                    }
+
+                    div class="form-group" {
+                        input type="submit" value="Create" class="btn btn-default" {}
+                    }
+                }
+            }
+        },
+    )
+}
 
-    Redirect::to(&format!("/class/{id}/teacher"))
+/// Contains the data submitted when the user creates a classroom
+#[derive(Deserialize)]
+pub struct CreateData {
+    /// The categories students choose from when opening a ticket, one per line
+    #[serde(default)]
+    categories: String,
 }
 
-/// The form presented to the user to join a classroom via a given 4-digit code
+/// Create a new clasroom, and redirect user to the teacher's view of the classroom. The teacher is
+/// given a cookie containing the class's secret token.
+pub async fn create(
+    State(state): State<AppState>,
+    Form(data): Form<CreateData>,
+) -> Result<Response, ClassError> {
+    let code = match state.create_class() {
+        Ok(code) => code,
+        Err(e) => {
+            // every class code is taken, present an error
+            let page = ui::base(
+                "Can't Create Class",
+                maud::html! {
+                    p { (e) "." }
+                    a href=(ui::url("/")) { "Go back." }
+                },
+            );
+
+            return Ok((StatusCode::SERVICE_UNAVAILABLE, page).into_response());
+        }
+    };
+
+    let token = state.with_tickets_mut(code, |t| {
+        t.set_categories(data.categories.lines());
+        t.teacher_token().clone()
+    })?;
+
+    Ok((
+        auth::teacher_cookie(code, &token),
+        Redirect::to(&ui::url(format!("/class/{code}/teacher"))),
+    )
+        .into_response())
+}
+
+/// Contains the token given in a class's admin link
+#[derive(Deserialize)]
+pub struct AdminArgs {
+    /// The class's secret teacher token
+    token: String,
+}
+
+/// Handler for a member of staff's admin link. If the token belongs to a member of staff, the user is given
+/// the teacher's cookie and redirected to the teacher's view.
+pub async fn admin(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<String>,
+    Query(args): Query<AdminArgs>,
+) -> Result<Response, ClassError> {
+    let code = state.lookup_code(ip, &id)?;
+
+    let member = state
+        .with_tickets(code, |t| t.member(&args.token))?
+        .ok_or(Forbidden::NotTeacher(code))?;
+
+    Ok((
+        auth::teacher_cookie(code, member.token()),
+        Redirect::to(&ui::url(format!("/class/{code}/teacher"))),
+    )
+        .into_response())
+}
+
+/// The form presented to the user to join a classroom via a given code
 pub async fn join_form() -> maud::Markup {
     ui::base(
         "Join Class",
         maud::html! {
-            form class="t-form" action="/join-class" method="post" {
+            form class="t-form" action=(ui::url("/join-class")) method="post" {
                 fieldset {
                     legend { "Class" }
 
@@ -35,12 +155,11 @@ pub async fn join_form() -> maud::Markup {
                         label for="code" { "Class Code: " }
                         input name="code"
                               type="text"
-                              placeholder="e.g. 1A3C"
-                              // ensure input is a valid hex digit
-                              pattern="[0-9a-fA-F]+"
-                              // input must be exactly 4 digits
-                              minlength="4"
-                              maxlength="4"
+                              placeholder="e.g. 7KQ2MX"
+                              // ensure input only contains letters and digits
+                              pattern="[0-9a-zA-Z]+"
+                              maxlength=(MAX_CODE_LEN)
+                              autocapitalize="characters"
                               required {}
                     }
 
@@ -56,51 +175,109 @@ pub async fn join_form() -> maud::Markup {
 /// Contains the data submitted when the user joins a classroom
 #[derive(Deserialize)]
 pub struct JoinData {
-    /// 4-digit hexadecimal code for the class. Validation handled by HTML form
+    /// Code for the class. Basic validation handled by HTML form
     code: String,
 }
 
-#[tracing::instrument]
+/// Contains the data submitted when a device subscribes to push notifications
+#[derive(Deserialize)]
+pub struct RegisterData {
+    /// The subscription, as provided by the browser
+    subscription: WebPushBuilder,
+    /// An (optional) name for the device, so the teacher can tell their devices apart
+    label: Option<String>,
+}
+
+/// Handler for the teacher subscribing a device to push notifications for a class. Subscribing a device
+/// that is already subscribed replaces its subscription.
+#[tracing::instrument(skip(headers, data))]
 #[axum::debug_handler]
 pub async fn register(
     State(state): State<AppState>,
-    Path(id): Path<u16>,
-    Json(builder): Json<WebPushBuilder>,
-) {
-    let sub = serde_json::to_string(&builder).unwrap();
-    tracing::debug!(id, sub, "user registered for class");
-    let Ok(code) = state.get_code(id) else {
-        tracing::error!(id, "unknown class");
-        return;
+    ClientIp(ip): ClientIp,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(data): Json<RegisterData>,
+) -> Result<(), ClassError> {
+    let code = state.lookup_code(ip, &id)?;
+
+    // only the teacher may receive notifications
+    auth::require_teacher(&state, code, &headers)?;
+
+    let label = data
+        .label
+        .filter(|l| !l.trim().is_empty())
+        .unwrap_or_else(|| "Unnamed device".to_string());
+    let sub = Subscriber::new(data.subscription, &label);
+    tracing::debug!(
+        label,
+        endpoint = sub.endpoint(),
+        "device subscribed to class"
+    );
+
+    state.with_tickets_mut(code, |tickets| tickets.subscribe(sub.clone()))?;
+
+    Ok(())
+}
+
+/// Contains the data submitted when a device unsubscribes from push notifications
+#[derive(Deserialize)]
+pub struct UnsubscribeData {
+    /// The push service endpoint of the device's subscription
+    endpoint: String,
+}
+
+/// Handler for the teacher unsubscribing a device from push notifications for a class, either from the
+/// device itself or from the list of devices in the teacher's view
+pub async fn unsubscribe(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Form(data): Form<UnsubscribeData>,
+) -> Result<Redirect, ClassError> {
+    let code = state.lookup_code(ip, &id)?;
+
+    // only the teacher may manage notifications
+    auth::require_teacher(&state, code, &headers)?;
+
+    if !state.with_tickets_mut(code, |tickets| tickets.unsubscribe(&data.endpoint))? {
+        tracing::debug!(%code, endpoint = data.endpoint, "device was not subscribed");
+    }
+
+    Ok(Redirect::to(&ui::url(format!("/class/{id}/teacher"))))
+}
+
+/// Present an error that occurred when looking up a class entered by the user
+pub fn lookup_error(e: LookupError) -> Response {
+    let status = match e {
+        LookupError::UnknownClass(_) => StatusCode::NOT_FOUND,
+        LookupError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
     };
 
-    state.with_tickets_mut(code, |tickets| tickets.subscribe(builder.clone()));
+    let page = ui::base(
+        "Unknown Class",
+        maud::html! {
+            p { (e) "." }
+            a href=(ui::url("/join-class")) { "Go back" }
+        },
+    );
+
+    (status, page).into_response()
 }
 
 /// Handler for the form submitted via [`join_form`]
 #[axum::debug_handler]
 pub async fn join_submit(
     State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
     Form(data): Form<JoinData>,
-) -> Result<Redirect, maud::Markup> {
-    // parse 4-digit hex number into a 16-bit integer
-    // this should never fail, as the HTML form enforces valid input, thus `expect` here
-    // should never panic
-    let code = u16::from_str_radix(&data.code, 16).expect("form produced invalid hex digit");
-
+) -> Result<Redirect, Response> {
     state
-        // retrieve class of the given code
-        .get_code(code)
+        // retrieve class of the given code, counting failures against the user
+        .lookup_code(ip, &data.code)
         // if class exists, redirect to student view
-        .map(|_| Redirect::to(&format!("/class/{code}/student")))
+        .map(|code| Redirect::to(&ui::url(format!("/class/{code}/student"))))
         // if class doesn't exist, present an error to user
-        .map_err(|e| {
-            ui::base(
-                "Unknown Class",
-                maud::html! {
-                    p { (e) "." }
-                    a href="/join-class" { "Go back" }
-                },
-            )
-        })
+        .map_err(lookup_error)
 }
@@ -0,0 +1,649 @@
+//! This module contains the server's configuration. No endpoints are defined in this module.
+//!
+//! Settings are layered, with each layer overriding those before it:
+//!
+//! 1. the defaults given by [`Config::default`]
+//! 2. the TOML config file (`summoner.toml` in the working directory, or the file given by `--config`)
+//! 3. `SUMMONER_*` environment variables
+//! 4. command-line flags
+//!
+//! The last two layers are both parsed by clap into [`Overrides`], which prefers a flag to its environment
+//! variable. `summoner config check` validates the resulting configuration, and prints it as TOML.
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+
+use clap::{Args, ValueEnum};
+
+use serde::{Deserialize, Serialize};
+
+use crate::state::{CodeFormat, CodeFormatError};
+
+/// The config file read when no other file is given, if it exists
+pub const DEFAULT_FILE: &str = "summoner.toml";
+
+/// The longest classes may be left without activity before they are closed, in minutes (a year)
+pub const MAX_CLASS_TIMEOUT: u64 = 60 * 24 * 365;
+
+/// Shown in place of secrets when printing the configuration
+const REDACTED: &str = "<redacted>";
+
+/// Error type when the configuration cannot be loaded, or is invalid
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to read config file {}: {1}", .0.display())]
+    Read(PathBuf, std::io::Error),
+    #[error("invalid config file {}: {1}", .0.display())]
+    Parse(PathBuf, toml::de::Error),
+    #[error("no config file given, and {DEFAULT_FILE} does not exist")]
+    NoFile,
+    #[error("no port given (set `port` in the config file, SUMMONER_PORT, or --port)")]
+    NoPort,
+    #[error("base path may only contain letters, digits, `/`, `-`, `.`, `_` and `~`")]
+    BasePath,
+    #[error("static directory {} does not exist", .0.display())]
+    StaticDir(PathBuf),
+    #[error("TLS needs both a certificate and a key")]
+    TlsIncomplete,
+    #[error("TLS file {} does not exist", .0.display())]
+    TlsFile(PathBuf),
+    #[error(
+        "the HTTP redirect listener needs TLS to be enabled, and a different port to the server"
+    )]
+    TlsRedirect,
+    #[error("VAPID subject must be a `mailto:` or `https:` URL")]
+    VapidSubject,
+    #[error("invalid class code format: {0}")]
+    CodeFormat(#[from] CodeFormatError),
+    #[error("class timeout must be at most {MAX_CLASS_TIMEOUT} minutes (a year)")]
+    ClassTimeout,
+}
+
+/// The server's configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The address to serve the application on
+    pub bind: IpAddr,
+    /// The port to serve the application on. There is no default, so it must be given in some layer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// The path the application is mounted at (e.g. `/summoner` when served behind a proxy at
+    /// `https://school.example/summoner/`). Empty to serve from the root.
+    pub base_path: String,
+    /// A directory to serve static files (JS and stylesheets) from instead of the copies compiled into the
+    /// executable, so they can be edited during development
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub static_dir: Option<PathBuf>,
+    /// The addresses of reverse proxies in front of the server. Requests from them are attributed to the
+    /// client given in their `X-Forwarded-For` header when limiting failed class code lookups.
+    pub trusted_proxies: Vec<IpAddr>,
+    pub log: LogConfig,
+    pub vapid: VapidConfig,
+    pub classes: ClassConfig,
+    pub storage: StorageConfig,
+    pub tls: TlsConfig,
+    pub metrics: MetricsConfig,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: None,
+            base_path: String::new(),
+            static_dir: None,
+            trusted_proxies: Vec::new(),
+            log: LogConfig::default(),
+            vapid: VapidConfig::default(),
+            classes: ClassConfig::default(),
+            storage: StorageConfig::default(),
+            tls: TlsConfig::default(),
+            metrics: MetricsConfig::default(),
+        }
+    }
+}
+
+/// The `[log]` section of the config file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// The most verbose level of messages to log
+    pub level: LogLevel,
+    /// How log messages are formatted
+    pub format: LogFormat,
+}
+
+/// The most verbose level of log messages to output
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Trace,
+    #[default]
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl From<LogLevel> for tracing::Level {
+    fn from(level: LogLevel) -> tracing::Level {
+        match level {
+            LogLevel::Trace => tracing::Level::TRACE,
+            LogLevel::Debug => tracing::Level::DEBUG,
+            LogLevel::Info => tracing::Level::INFO,
+            LogLevel::Warn => tracing::Level::WARN,
+            LogLevel::Error => tracing::Level::ERROR,
+        }
+    }
+}
+
+/// The format log messages are output in, see [`tracing_subscriber::fmt::format`]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One line per message, including its fields
+    #[default]
+    Full,
+    /// A shorter single line per message
+    Compact,
+    /// Multiple lines per message, for reading by humans
+    Pretty,
+}
+
+/// The `[vapid]` section of the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VapidConfig {
+    /// Contact details sent to push services alongside the VAPID signature
+    pub subject: String,
+    /// The PEM/DER file containing the VAPID key pair, generated if missing
+    pub key: PathBuf,
+}
+
+impl Default for VapidConfig {
+    fn default() -> VapidConfig {
+        VapidConfig {
+            subject: "mailto:jcbsnclr@outlook.com".to_string(),
+            key: PathBuf::from("vapid.pem"),
+        }
+    }
+}
+
+/// The `[classes]` section of the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClassConfig {
+    /// Close classes after this many minutes without activity (0 to never close them), up to
+    /// [`MAX_CLASS_TIMEOUT`]
+    pub timeout: u64,
+    /// The number of characters in new class codes
+    pub code_length: usize,
+    /// The characters new class codes are made from
+    pub code_alphabet: String,
+    /// Refuse class lookups from an address after this many unknown codes in 10 minutes (0 to never refuse)
+    pub max_failed_lookups: usize,
+}
+
+impl Default for ClassConfig {
+    fn default() -> ClassConfig {
+        ClassConfig {
+            timeout: 240,
+            code_length: 6,
+            code_alphabet: CodeFormat::DEFAULT_ALPHABET.to_string(),
+            max_failed_lookups: 10,
+        }
+    }
+}
+
+/// The `[storage]` section of the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// The SQLite database to store classes in (`:memory:` to disable persistence)
+    pub database: PathBuf,
+}
+
+impl Default for StorageConfig {
+    fn default() -> StorageConfig {
+        StorageConfig {
+            database: PathBuf::from("summoner.db"),
+        }
+    }
+}
+
+/// The `[tls]` section of the config file. TLS is enabled when a certificate and key are given.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// The PEM file containing the certificate chain, reloaded whenever it changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cert: Option<PathBuf>,
+    /// The PEM file containing the certificate's private key, reloaded whenever it changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<PathBuf>,
+    /// A port to listen for plain HTTP on, redirecting every request to HTTPS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_port: Option<u16>,
+}
+
+/// The `[metrics]` section of the config file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// The bearer token Prometheus must send to scrape `/metrics`. Without one, only clients on the same
+    /// machine may scrape it, and not at all when the server is behind a proxy (see [`metrics`]).
+    ///
+    /// [`metrics`]: crate::metrics
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl Config {
+    /// Load the config file at the given path, or [`DEFAULT_FILE`] if none is given. Returns the defaults
+    /// if no path is given and the default file does not exist.
+    pub fn load(path: Option<&Path>) -> Result<(Config, Option<PathBuf>), ConfigError> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None if Path::new(DEFAULT_FILE).exists() => PathBuf::from(DEFAULT_FILE),
+            None => return Ok((Config::default(), None)),
+        };
+
+        let text =
+            std::fs::read_to_string(&path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+        let config = toml::from_str(&text).map_err(|e| ConfigError::Parse(path.clone(), e))?;
+
+        Ok((config, Some(path)))
+    }
+
+    /// Check that the configuration describes a server that can be run
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.port()?;
+        self.code_format()?;
+
+        if self.classes.timeout > MAX_CLASS_TIMEOUT {
+            return Err(ConfigError::ClassTimeout);
+        }
+
+        let valid = |c: char| c.is_ascii_alphanumeric() || "/-._~".contains(c);
+        if !self.base_path.chars().all(valid) {
+            return Err(ConfigError::BasePath);
+        }
+
+        if let Some(dir) = self.static_dir.as_ref().filter(|d| !d.is_dir()) {
+            return Err(ConfigError::StaticDir(dir.clone()));
+        }
+
+        match (&self.tls.cert, &self.tls.key) {
+            (Some(cert), Some(key)) => {
+                for file in [cert, key] {
+                    if !file.is_file() {
+                        return Err(ConfigError::TlsFile(file.clone()));
+                    }
+                }
+            }
+            (None, None) => {}
+            _ => return Err(ConfigError::TlsIncomplete),
+        }
+
+        if let Some(redirect) = self.tls.redirect_port {
+            if self.tls.cert.is_none() || self.port == Some(redirect) {
+                return Err(ConfigError::TlsRedirect);
+            }
+        }
+
+        if !["mailto:", "https:"]
+            .iter()
+            .any(|s| self.vapid.subject.starts_with(s))
+        {
+            return Err(ConfigError::VapidSubject);
+        }
+
+        Ok(())
+    }
+
+    /// How long classes may be left without activity before they are closed, or `None` if they are never
+    /// closed. The configuration must have been validated.
+    pub fn class_timeout(&self) -> Option<chrono::Duration> {
+        // validation ensures the timeout fits in a duration
+        (self.classes.timeout > 0).then(|| chrono::Duration::minutes(self.classes.timeout as i64))
+    }
+
+    /// A copy of the configuration with any secrets hidden, so that it can be printed
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+
+        if config.metrics.token.is_some() {
+            config.metrics.token = Some(REDACTED.to_string());
+        }
+
+        config
+    }
+
+    /// The format of codes generated for new classes
+    pub fn code_format(&self) -> Result<CodeFormat, CodeFormatError> {
+        CodeFormat::new(self.classes.code_length, &self.classes.code_alphabet)
+    }
+
+    /// The path the application is mounted at, with a leading slash and no trailing slash (e.g. `/summoner`),
+    /// or an empty string when mounted at the root
+    pub fn base_path(&self) -> String {
+        let path = self.base_path.trim_matches('/');
+
+        if path.is_empty() {
+            String::new()
+        } else {
+            format!("/{path}")
+        }
+    }
+
+    /// The certificate and key files to serve the application over TLS with, if TLS is enabled
+    pub fn tls(&self) -> Option<(&Path, &Path)> {
+        Some((self.tls.cert.as_deref()?, self.tls.key.as_deref()?))
+    }
+
+    /// The port to serve the application on
+    pub fn port(&self) -> Result<u16, ConfigError> {
+        self.port.ok_or(ConfigError::NoPort)
+    }
+}
+
+/// Settings given by environment variables and command-line flags, which override those in the config file
+#[derive(Args, Debug)]
+pub struct Overrides {
+    #[arg(
+        short,
+        long,
+        env = "SUMMONER_PORT",
+        help = "the port to serve the application on"
+    )]
+    port: Option<u16>,
+
+    #[arg(
+        short,
+        long,
+        env = "SUMMONER_BIND",
+        help = "the address to serve the application on [default: 127.0.0.1]"
+    )]
+    bind: Option<IpAddr>,
+
+    #[arg(
+        long,
+        env = "SUMMONER_BASE_PATH",
+        help = "the path the application is mounted at, when served behind a proxy (e.g. `/summoner`)"
+    )]
+    base_path: Option<String>,
+
+    #[arg(
+        long,
+        env = "SUMMONER_STATIC_DIR",
+        help = "serve static files from this directory instead of the executable, for development"
+    )]
+    static_dir: Option<PathBuf>,
+
+    #[arg(
+        long = "trusted-proxy",
+        env = "SUMMONER_TRUSTED_PROXIES",
+        value_delimiter = ',',
+        help = "the address of a reverse proxy whose X-Forwarded-For header is trusted (may be repeated)"
+    )]
+    trusted_proxies: Option<Vec<IpAddr>>,
+
+    #[arg(
+        long,
+        env = "SUMMONER_LOG_LEVEL",
+        help = "the most verbose messages to log [default: debug]"
+    )]
+    log_level: Option<LogLevel>,
+
+    #[arg(
+        long,
+        env = "SUMMONER_LOG_FORMAT",
+        help = "the format to log messages in [default: full]"
+    )]
+    log_format: Option<LogFormat>,
+
+    #[arg(
+        long,
+        env = "SUMMONER_VAPID_SUBJECT",
+        help = "the `mailto:` or `https:` contact URL sent to push services"
+    )]
+    vapid_subject: Option<String>,
+
+    #[arg(
+        short,
+        long,
+        global = true,
+        env = "SUMMONER_VAPID_KEY",
+        help = "the PEM/DER file containing the VAPID key pair (generated if missing) [default: vapid.pem]"
+    )]
+    vapid_key: Option<PathBuf>,
+
+    #[arg(
+        short = 't',
+        long,
+        env = "SUMMONER_CLASS_TIMEOUT",
+        help = "close classes after this many minutes without activity (0 to never close them) [default: 240]"
+    )]
+    class_timeout: Option<u64>,
+
+    #[arg(
+        long,
+        env = "SUMMONER_CODE_LENGTH",
+        help = "the number of characters in new class codes [default: 6]"
+    )]
+    code_length: Option<usize>,
+
+    #[arg(
+        long,
+        env = "SUMMONER_CODE_ALPHABET",
+        help = "the characters new class codes are made from"
+    )]
+    code_alphabet: Option<String>,
+
+    #[arg(
+        long,
+        env = "SUMMONER_MAX_FAILED_LOOKUPS",
+        help = "refuse class lookups from an address after this many unknown codes in 10 minutes (0 to never refuse) [default: 10]"
+    )]
+    max_failed_lookups: Option<usize>,
+
+    #[arg(
+        short,
+        long,
+        env = "SUMMONER_DATABASE",
+        help = "the SQLite database to store classes in (`:memory:` to disable persistence) [default: summoner.db]"
+    )]
+    database: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "SUMMONER_TLS_CERT",
+        help = "the PEM file containing the TLS certificate chain"
+    )]
+    tls_cert: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "SUMMONER_TLS_KEY",
+        help = "the PEM file containing the TLS private key"
+    )]
+    tls_key: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "SUMMONER_TLS_REDIRECT_PORT",
+        help = "a port to redirect plain HTTP requests to HTTPS from"
+    )]
+    tls_redirect_port: Option<u16>,
+
+    #[arg(
+        long,
+        env = "SUMMONER_METRICS_TOKEN",
+        help = "the bearer token required to scrape /metrics (if not given, only local clients not behind a proxy may scrape it)"
+    )]
+    metrics_token: Option<String>,
+}
+
+impl Overrides {
+    /// Replace the settings in a configuration with any that were given
+    pub fn apply(&self, config: &mut Config) {
+        /// Overwrite a setting if an override was given for it
+        fn set<T: Clone>(setting: &mut T, value: &Option<T>) {
+            if let Some(value) = value {
+                *setting = value.clone();
+            }
+        }
+
+        /// Overwrite an optional setting if an override was given for it
+        fn set_some<T: Clone>(setting: &mut Option<T>, value: &Option<T>) {
+            if value.is_some() {
+                setting.clone_from(value);
+            }
+        }
+
+        set_some(&mut config.port, &self.port);
+        set(&mut config.bind, &self.bind);
+        set(&mut config.base_path, &self.base_path);
+        set_some(&mut config.static_dir, &self.static_dir);
+        set(&mut config.trusted_proxies, &self.trusted_proxies);
+        set(&mut config.log.level, &self.log_level);
+        set(&mut config.log.format, &self.log_format);
+        set(&mut config.vapid.subject, &self.vapid_subject);
+        set(&mut config.vapid.key, &self.vapid_key);
+        set(&mut config.classes.timeout, &self.class_timeout);
+        set(&mut config.classes.code_length, &self.code_length);
+        set(&mut config.classes.code_alphabet, &self.code_alphabet);
+        set(
+            &mut config.classes.max_failed_lookups,
+            &self.max_failed_lookups,
+        );
+        set(&mut config.storage.database, &self.database);
+        set_some(&mut config.tls.cert, &self.tls_cert);
+        set_some(&mut config.tls.key, &self.tls_key);
+        set_some(&mut config.tls.redirect_port, &self.tls_redirect_port);
+        set_some(&mut config.metrics.token, &self.metrics_token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    /// A configuration that passes validation
+    fn valid() -> Config {
+        Config {
+            port: Some(8080),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn validate() {
+        assert!(valid().validate().is_ok());
+        assert!(matches!(
+            Config::default().validate(),
+            Err(ConfigError::NoPort)
+        ));
+
+        /// Validate a valid configuration after changing it
+        fn with(change: impl FnOnce(&mut Config)) -> Result<(), ConfigError> {
+            let mut config = valid();
+            change(&mut config);
+            config.validate()
+        }
+
+        assert!(matches!(
+            with(|c| c.classes.code_length = 2),
+            Err(ConfigError::CodeFormat(_))
+        ));
+        assert!(matches!(
+            with(|c| c.classes.timeout = MAX_CLASS_TIMEOUT + 1),
+            Err(ConfigError::ClassTimeout)
+        ));
+        assert!(matches!(
+            with(|c| c.base_path = "/a b".to_string()),
+            Err(ConfigError::BasePath)
+        ));
+        assert!(matches!(
+            with(|c| c.tls.cert = Some(PathBuf::from("cert.pem"))),
+            Err(ConfigError::TlsIncomplete)
+        ));
+        assert!(matches!(
+            with(|c| c.tls.redirect_port = Some(80)),
+            Err(ConfigError::TlsRedirect)
+        ));
+        assert!(matches!(
+            with(|c| c.vapid.subject = "admin@example.com".to_string()),
+            Err(ConfigError::VapidSubject)
+        ));
+
+        let mut config = valid();
+        config.classes.timeout = MAX_CLASS_TIMEOUT;
+        config.base_path = "/summoner/".to_string();
+        assert!(config.validate().is_ok());
+    }
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        overrides: Overrides,
+    }
+
+    #[test]
+    fn layers_override_each_other() {
+        let mut config: Config = toml::from_str(
+            r#"
+            port = 8000
+            base_path = "/file"
+
+            [classes]
+            code_length = 7
+            timeout = 30
+            "#,
+        )
+        .unwrap();
+
+        // this is the only test that reads the environment, so it can't race with another
+        std::env::set_var("SUMMONER_PORT", "8001");
+        std::env::set_var("SUMMONER_CODE_LENGTH", "8");
+        std::env::set_var("SUMMONER_TRUSTED_PROXIES", "10.0.0.1,10.0.0.2");
+        let cli = Cli::try_parse_from(["summoner", "--port", "8002", "--metrics-token", "secret"]);
+        for var in [
+            "SUMMONER_PORT",
+            "SUMMONER_CODE_LENGTH",
+            "SUMMONER_TRUSTED_PROXIES",
+        ] {
+            std::env::remove_var(var);
+        }
+
+        cli.unwrap().overrides.apply(&mut config);
+
+        // flags beat environment variables, which beat the file, which beats the defaults
+        assert_eq!(config.port, Some(8002));
+        assert_eq!(config.classes.code_length, 8);
+        assert_eq!(
+            config.trusted_proxies,
+            [
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "10.0.0.2".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.metrics.token.as_deref(), Some("secret"));
+        assert_eq!(config.base_path, "/file");
+        assert_eq!(config.classes.timeout, 30);
+        assert_eq!(config.classes.code_alphabet, CodeFormat::DEFAULT_ALPHABET);
+        assert_eq!(config.bind, IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let mut config = valid();
+        config.metrics.token = Some("secret".to_string());
+
+        let printed = toml::to_string(&config.redacted()).unwrap();
+        assert!(!printed.contains("secret") && printed.contains(REDACTED));
+    }
+}
@@ -0,0 +1,136 @@
+//! This module defines the live event streams for a class, used by the teacher's view to update the list of
+//! tickets as soon as it changes, and by the student's view to update the status of their ticket.
+//!
+//! This module is used to define the following endpoints:
+//!   * *GET* `/class/{id}/events`                 ([`stream`])
+//!   * *GET* `/class/{id}/ticket/{ticket}/events` ([`ticket_stream`])
+//!
+//! Events are published to a per-class broadcast channel in [`AppState`], and forwarded to clients as
+//! [Server-Sent Events](https://developer.mozilla.org/en-US/docs/Web/API/Server-sent_events). Each event has
+//! one of the following types:
+//!   * `ticket-added` - data is the rendered HTML of the new ticket
+//!   * `ticket-updated` - data is the re-rendered HTML of a ticket whose status changed, or that was sent a
+//!     message
+//!   * `ticket-dismissed` - data is the ID of a ticket that was resolved or cancelled
+//!   * `class-closed` - the class no longer exists, data is empty
+//!   * `resync` - the client fell behind and missed events, or the order of the queue changed, so should
+//!     re-fetch the whole list
+//!
+//! The student's stream instead sends a `status` event, containing the re-rendered status of their ticket,
+//! whenever anything in the class changes (as this may move them up the queue), along with `class-closed`.
+//! It may only be opened by the student that opened the ticket or by the class's staff, and only the
+//! student is sent a `messages` event containing the re-rendered thread whenever a message is posted to it.
+
+use std::convert::Infallible;
+
+use axum::extract::{Path, State};
+use axum::http::HeaderMap;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+
+use maud::Render;
+
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+
+use crate::auth;
+use crate::class::ClassError;
+use crate::ratelimit::ClientIp;
+use crate::state::AppState;
+use crate::student;
+use crate::ticket::{Ticket, TicketId};
+
+/// An event that occurs within a class
+#[derive(Debug, Clone)]
+pub enum ClassEvent {
+    /// A student opened a new ticket
+    TicketAdded(Ticket),
+    /// A ticket's status changed, but it is still open
+    TicketUpdated(Ticket),
+    /// A ticket was resolved or cancelled
+    TicketDismissed(TicketId),
+    /// A message was posted to a ticket's thread
+    MessagePosted(Ticket),
+    /// The order of the queue may have changed, so the whole list should be re-fetched
+    Reordered,
+    /// The class was closed
+    ClassClosed,
+}
+
+impl ClassEvent {
+    /// Convert the event into a Server-Sent Event
+    fn to_sse(&self) -> Event {
+        match self {
+            ClassEvent::TicketAdded(ticket) => Event::default()
+                .event("ticket-added")
+                .data(ticket.render().into_string()),
+            ClassEvent::TicketUpdated(ticket) | ClassEvent::MessagePosted(ticket) => {
+                Event::default()
+                    .event("ticket-updated")
+                    .data(ticket.render().into_string())
+            }
+            ClassEvent::TicketDismissed(id) => Event::default()
+                .event("ticket-dismissed")
+                .data(id.as_usize().to_string()),
+            ClassEvent::Reordered => Event::default().event("resync").data(""),
+            ClassEvent::ClassClosed => Event::default().event("class-closed").data(""),
+        }
+    }
+}
+
+/// Handler for the teacher's event stream. Only accessible to the class's teacher.
+pub async fn stream(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ClassError> {
+    let code = state.lookup_code(ip, &id)?;
+
+    auth::require_teacher(&state, code, &headers)?;
+
+    let events = BroadcastStream::new(state.listen(code)).map(|event| {
+        Ok::<_, Infallible>(match event {
+            Ok(event) => event.to_sse(),
+            // the receiver lagged behind and events were dropped, tell the client to re-fetch the list
+            Err(_) => Event::default().event("resync").data(""),
+        })
+    });
+
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+/// Handler for a student's event stream, which follows the status of a single ticket. Only the student that
+/// opened the ticket, and the class's staff, may follow it, and messages are only sent to the student.
+pub async fn ticket_stream(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path((id, ticket)): Path<(String, TicketId)>,
+    headers: HeaderMap,
+) -> Result<Response, ClassError> {
+    let code = state.lookup_code(ip, &id)?;
+    auth::require_viewer(&state, code, ticket, &headers)?;
+
+    let owner = auth::require_owner(&state, code, ticket, &headers).is_ok();
+
+    let events = BroadcastStream::new(state.listen(code)).map(move |event| {
+        Ok::<_, Infallible>(match event {
+            Ok(ClassEvent::ClassClosed) => Event::default().event("class-closed").data(""),
+            Ok(ClassEvent::MessagePosted(t)) if owner && t.id() == ticket => Event::default()
+                .event("messages")
+                .data(t.thread().into_string()),
+            // any other change (or missed changes) may affect the ticket, so send its current status
+            _ => match state.with_tickets(code, |t| student::status(t, ticket)) {
+                Ok(status) => Event::default().event("status").data(status.into_string()),
+                // we missed the class being closed
+                Err(_) => Event::default().event("class-closed").data(""),
+            },
+        })
+    });
+
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
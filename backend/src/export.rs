@@ -0,0 +1,320 @@
+//! This module defines the export of a class's tickets, so that they can be kept after the class is closed.
+//!
+//! This module is used to define the endpoint *GET* `/class/{id}/export` ([`export`]), which accepts three
+//! arguments in the query part of the URL:
+//!   * `format: Format` - either `csv` (the default) or `json`
+//!   * `from: NaiveDate` - if provided, only export tickets opened on or after this date (`YYYY-MM-DD`)
+//!   * `to: NaiveDate` - if provided, only export tickets opened on or before this date
+//!
+//! Dates are in the server's local time zone. The CSV export has one row per ticket (see [`Row`]), while the
+//! JSON export contains every ticket in full, as returned by the JSON API (see [`crate::api::TicketInfo`]).
+//! Exports include the staff's private notes, so are only available to staff that can see them (see
+//! [`Permission::ExportTickets`]).
+
+use axum::extract::{Path, Query, State};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::api::TicketInfo;
+use crate::auth::{self, Forbidden};
+use crate::ratelimit::ClientIp;
+use crate::staff::Permission;
+use crate::state::{AppState, LookupError, UnknownClass};
+use crate::ticket::{Status, Ticket};
+use crate::ui;
+
+/// Error type for failures to export a class's tickets
+#[derive(thiserror::Error, Debug)]
+pub enum ExportError {
+    #[error(transparent)]
+    UnknownClass(#[from] UnknownClass),
+    /// Too many unknown class codes have been given by the client
+    #[error(transparent)]
+    RateLimited(LookupError),
+    #[error(transparent)]
+    Forbidden(#[from] Forbidden),
+    /// A date in the range couldn't be parsed
+    #[error("Invalid date {0:?}, expected YYYY-MM-DD")]
+    InvalidDate(String),
+    /// The tickets couldn't be written as CSV
+    #[error("Failed to write CSV: {0}")]
+    Csv(#[from] csv::Error),
+}
+
+impl From<LookupError> for ExportError {
+    fn from(e: LookupError) -> ExportError {
+        match e {
+            LookupError::UnknownClass(e) => ExportError::UnknownClass(e),
+            e @ LookupError::RateLimited => ExportError::RateLimited(e),
+        }
+    }
+}
+
+impl IntoResponse for ExportError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ExportError::Forbidden(e) => return e.into_response(),
+            ExportError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ExportError::UnknownClass(_) => StatusCode::NOT_FOUND,
+            ExportError::InvalidDate(_) => StatusCode::BAD_REQUEST,
+            ExportError::Csv(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        let page = ui::base(
+            "Can't Export Tickets",
+            maud::html! {
+                p { (self) "." }
+                a href=(ui::url("/")) { "Go back." };
+            },
+        );
+
+        (status, page).into_response()
+    }
+}
+
+/// The format to export tickets in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    #[default]
+    Csv,
+    Json,
+}
+
+/// The URL query arguments to the export
+#[derive(Deserialize)]
+pub struct ExportArgs {
+    /// The format to export tickets in
+    #[serde(default)]
+    format: Format,
+    /// The first day to include tickets from. Given as a string, as HTML forms send empty dates
+    from: Option<String>,
+    /// The last day to include tickets from
+    to: Option<String>,
+}
+
+/// Parse an (optional) date given in the query, treating an empty string as no date
+fn parse_date(date: Option<&str>) -> Result<Option<NaiveDate>, ExportError> {
+    match date.map(str::trim).filter(|d| !d.is_empty()) {
+        Some(d) => NaiveDate::parse_from_str(d, "%Y-%m-%d")
+            .map(Some)
+            .map_err(|_| ExportError::InvalidDate(d.to_string())),
+        None => Ok(None),
+    }
+}
+
+/// Returns `true` if a ticket opened at the given time was opened within the range of (local) dates, which
+/// includes both ends
+fn in_range(opened: DateTime<Utc>, from: Option<NaiveDate>, to: Option<NaiveDate>) -> bool {
+    let opened = opened.with_timezone(&Local).date_naive();
+    from.is_none_or(|from| opened >= from) && to.is_none_or(|to| opened <= to)
+}
+
+/// A single ticket in the CSV export
+#[derive(Debug, Serialize)]
+pub struct Row {
+    id: usize,
+    student: String,
+    description: String,
+    category: String,
+    status: Status,
+    created: DateTime<Utc>,
+    /// When a member of staff first claimed or started helping with the ticket
+    claimed: Option<DateTime<Utc>>,
+    /// When the ticket was resolved or cancelled
+    resolved: Option<DateTime<Utc>>,
+    /// Who resolved or cancelled the ticket
+    resolver: String,
+    summary: String,
+    /// Every note, one per line, in the form `author: text`
+    notes: String,
+}
+
+/// Make text typed by a user safe to put in a CSV cell. Spreadsheets run cells beginning with any of these
+/// characters as formulas, so they are prefixed with `'`, which makes the cell plain text.
+fn cell(text: &str) -> String {
+    if text.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{text}")
+    } else {
+        text.to_string()
+    }
+}
+
+impl From<&Ticket> for Row {
+    fn from(ticket: &Ticket) -> Row {
+        let closed = ticket.last_transition().filter(|t| !t.status.is_open());
+        let claimed = ticket
+            .history()
+            .iter()
+            .find(|t| matches!(t.status, Status::Claimed | Status::InProgress))
+            .map(|t| t.timestamp);
+        let notes: Vec<_> = ticket
+            .notes()
+            .iter()
+            .map(|n| format!("{}: {}", n.author, n.text))
+            .collect();
+
+        Row {
+            id: ticket.id().as_usize(),
+            student: cell(ticket.student()),
+            description: cell(ticket.desc().unwrap_or_default()),
+            category: cell(ticket.category().unwrap_or_default()),
+            status: ticket.status(),
+            created: ticket.created(),
+            claimed,
+            resolved: closed.map(|t| t.timestamp),
+            resolver: cell(&closed.map(|t| t.actor.to_string()).unwrap_or_default()),
+            summary: cell(ticket.summary().unwrap_or_default()),
+            notes: cell(&notes.join("\n")),
+        }
+    }
+}
+
+/// Write tickets as CSV, with a header row
+fn to_csv(tickets: &[Ticket]) -> Result<Vec<u8>, ExportError> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+
+    for ticket in tickets {
+        writer.serialize(Row::from(ticket))?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|e| ExportError::Csv(e.into_error().into()))
+}
+
+/// Handler for a member of staff downloading the class's tickets, in the order they were opened
+pub async fn export(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<String>,
+    Query(args): Query<ExportArgs>,
+    headers: HeaderMap,
+) -> Result<Response, ExportError> {
+    let code = state.lookup_code(ip, &id)?;
+
+    auth::require_teacher(&state, code, &headers)?.require(Permission::ExportTickets)?;
+
+    let from = parse_date(args.from.as_deref())?;
+    let to = parse_date(args.to.as_deref())?;
+
+    let tickets: Vec<Ticket> = state.with_tickets(code, |t| {
+        t.tickets()
+            .filter(|t| in_range(t.created(), from, to))
+            .cloned()
+            .collect()
+    })?;
+
+    let (body, content_type, ext) = match args.format {
+        Format::Csv => (to_csv(&tickets)?, "text/csv; charset=utf-8", "csv"),
+        Format::Json => {
+            let tickets: Vec<_> = tickets.into_iter().map(TicketInfo::from).collect();
+            // serialising our own types can't fail
+            let json = serde_json::to_vec_pretty(&tickets).expect("tickets should serialise");
+            (json, "application/json", "json")
+        }
+    };
+
+    // prompt the browser to save the file, rather than display it
+    let disposition = format!("attachment; filename=\"class-{code}-tickets.{ext}\"");
+
+    Ok((
+        [
+            (CONTENT_TYPE, content_type.to_string()),
+            (CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::ticket::TicketList;
+
+    #[test]
+    fn formulas_are_escaped() {
+        for trigger in ['=', '+', '-', '@', '\t', '\r'] {
+            let text = format!("{trigger}SUM(A1:A9)");
+            assert_eq!(cell(&text), format!("'{text}"));
+        }
+
+        // triggers are only dangerous at the start of a cell
+        assert_eq!(cell("1+1=2"), "1+1=2");
+        assert_eq!(cell(""), "");
+    }
+
+    #[test]
+    fn csv_quotes_commas_and_quotes() {
+        let mut list = TicketList::new();
+        list.add_ticket(
+            "Smith, Jo",
+            Some("the \"hello\" example"),
+            None::<&str>,
+            None,
+        )
+        .unwrap();
+        list.add_ticket("Al", Some("=HYPERLINK(\"x\")"), None::<&str>, None)
+            .unwrap();
+
+        let tickets: Vec<_> = list.tickets().cloned().collect();
+        let csv = String::from_utf8(to_csv(&tickets).unwrap()).unwrap();
+        let rows: Vec<_> = csv.lines().collect();
+
+        assert!(rows[0].starts_with("id,student,description,category,status,created,"));
+        assert!(rows[1].starts_with(r#"0,"Smith, Jo","the ""hello"" example",,open,"#));
+        assert!(rows[2].starts_with(r#"1,Al,"'=HYPERLINK(""x"")",,open,"#));
+
+        // the rows read back as the original (escaped) text
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        let records: Vec<_> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(&records[0][1], "Smith, Jo");
+        assert_eq!(&records[1][2], "'=HYPERLINK(\"x\")");
+    }
+
+    #[test]
+    fn date_range_includes_both_ends() {
+        let day = NaiveDate::from_ymd_opt(2024, 3, 14).unwrap();
+        let (before, after) = (day.pred_opt().unwrap(), day.succ_opt().unwrap());
+
+        // noon, in the server's time zone
+        let opened = Local
+            .from_local_datetime(&day.and_hms_opt(12, 0, 0).unwrap())
+            .unwrap()
+            .with_timezone(&Utc);
+
+        assert!(in_range(opened, None, None));
+        assert!(in_range(opened, Some(day), Some(day)));
+        assert!(in_range(opened, Some(before), Some(after)));
+
+        assert!(!in_range(opened, Some(after), None));
+        assert!(!in_range(opened, None, Some(before)));
+    }
+
+    #[test]
+    fn dates_are_parsed() {
+        assert_eq!(
+            parse_date(Some("2024-03-14")).unwrap(),
+            NaiveDate::from_ymd_opt(2024, 3, 14)
+        );
+        // forms send empty dates when none is chosen
+        assert_eq!(parse_date(Some(" ")).unwrap(), None);
+        assert_eq!(parse_date(None).unwrap(), None);
+
+        assert!(matches!(
+            parse_date(Some("14/03/2024")),
+            Err(ExportError::InvalidDate(_))
+        ));
+        assert!(matches!(
+            parse_date(Some("2024-02-30")),
+            Err(ExportError::InvalidDate(_))
+        ));
+    }
+}
@@ -1,75 +1,279 @@
 //! Teacher Summoner, a simple web-app to request help from your teacher.
 //!
 //! Allows users to create a "class", in which other users can open "tickets" to request help.
-//! Users can join classes via a 4-digit hexadecimal code. They will be presented with an error if
-//! a class does not exist with a given code.
+//! Users can join classes via a randomly generated code (6 letters and digits by default). They will be
+//! presented with an error if a class does not exist with a given code, and will be temporarily refused if
+//! they enter too many unknown codes.
 //!
 //! The creator of a class is presented with a dynamic view of open tickets. They are able to dismiss
-//! tickets as they see to them.
+//! tickets as they see to them. Only the creator, and any co-teachers and teaching assistants they invite,
+//! can access this view.
 
+mod api;
+mod assets;
+mod auth;
 mod class;
+mod config;
+mod events;
+mod export;
+mod metrics;
+mod push;
+mod queue;
+mod ratelimit;
+mod staff;
 mod state;
+mod stats;
+mod storage;
 mod student;
 mod teacher;
 mod ticket;
+mod tls;
 mod ui;
+mod vapid;
 
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use axum::extract::State;
+use axum::middleware;
 use axum::routing::{get, post};
 use axum::Router;
 
-use clap::Parser;
+use axum_server::Handle;
 
-use base64ct::{Base64UrlUnpadded, Encoding};
-
-use web_push_native::jwt_simple::algorithms::ECDSAP256KeyPairLike;
+use clap::{Parser, Subcommand};
 
 use serde::Serialize;
 
+use config::{Config, ConfigError, LogFormat};
+use ratelimit::RateLimiter;
 use state::AppState;
-use tower_http::services::ServeDir;
 use tower_livereload::LiveReloadLayer;
 
+/// How long open requests are given to complete when the server is shut down
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
 #[derive(Parser)]
 #[command(author, version, about)]
 struct Cmdline {
-    #[arg(short, long, help = "the port to serve the application on")]
-    port: u16,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(
+        short,
+        long,
+        global = true,
+        env = "SUMMONER_CONFIG",
+        help = "the TOML config file to read settings from [default: summoner.toml, if it exists]"
+    )]
+    config: Option<PathBuf>,
 
     #[arg(short, long, help = "auto-reload clients when server restarts")]
     reload: bool,
+
+    #[command(flatten)]
+    overrides: config::Overrides,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "print the server's VAPID public key, generating a key pair if needed")]
+    Keygen,
+    #[command(subcommand, about = "inspect the server's configuration")]
+    Config(ConfigCommand),
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    #[command(
+        about = "validate the config file, and print the configuration that results from it"
+    )]
+    Check,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::DEBUG)
-        .init();
-
     let args = Cmdline::parse();
 
+    // layer the config file, environment variables and flags over the defaults
+    let (mut config, file) = Config::load(args.config.as_deref())?;
+    args.overrides.apply(&mut config);
+
+    if let Some(Command::Config(ConfigCommand::Check)) = args.command {
+        let file = file.ok_or(ConfigError::NoFile)?;
+        config.validate()?;
+
+        eprintln!("{} is valid", file.display());
+        print!("{}", toml::to_string(&config.redacted())?);
+        return Ok(());
+    }
+
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::from(config.log.level))
+        // log to stderr, so output of subcommands (e.g. `keygen`) can be piped elsewhere
+        .with_writer(std::io::stderr);
+
+    match config.log.format {
+        LogFormat::Full => subscriber.init(),
+        LogFormat::Compact => subscriber.compact().init(),
+        LogFormat::Pretty => subscriber.pretty().init(),
+    }
+
+    if let Some(file) = &file {
+        tracing::info!(path = %file.display(), "loaded config file");
+    }
+
+    // load the VAPID key, so existing push subscriptions remain valid
+    let vapid_key = vapid::load_or_create(&config.vapid.key)?;
+
+    if let Some(Command::Keygen) = args.command {
+        println!("{}", vapid::public_key(&vapid_key));
+        return Ok(());
+    }
+
+    config.validate()?;
+    let port = config.port()?;
+
+    // generated links, redirects and cookies are prefixed with the path the app is mounted at
+    let base_path = config.base_path();
+    ui::set_base_path(base_path.clone());
+
+    let code_format = config.code_format()?;
+    let limiter = RateLimiter::new(
+        config.classes.max_failed_lookups,
+        std::time::Duration::from_secs(10 * 60),
+        config.trusted_proxies.clone(),
+    );
+
+    // load persisted classes from the database
+    let state = AppState::init(
+        storage::open(&config.storage.database)?,
+        vapid_key,
+        config.vapid.subject.clone(),
+        code_format,
+        limiter,
+        metrics::Metrics::new(
+            config.metrics.token.clone(),
+            !base_path.is_empty() || !config.trusted_proxies.is_empty(),
+        ),
+    )?;
+
+    if let Some(timeout) = config.class_timeout() {
+        // periodically close classes that have been abandoned
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+
+            loop {
+                interval.tick().await;
+                state.expire_classes(timeout);
+            }
+        });
+    }
+
     let app = Router::new()
         // index page for site
         .route("/", get(root))
         // for browsers to get VAPID public key
         .route("/api/vapid.json", get(vapid))
+        // for Prometheus to scrape
+        .route("/metrics", get(metrics::metrics))
+        // JSON API for scripts and bots
+        .route("/api/v1/classes", post(api::create_class))
+        .route(
+            "/api/v1/classes/:id",
+            get(api::get_class).delete(api::close_class),
+        )
+        .route(
+            "/api/v1/classes/:id/tickets",
+            get(api::list_tickets).post(api::create_ticket),
+        )
+        .route("/api/v1/classes/:id/tickets/:ticket", get(api::get_ticket))
+        .route(
+            "/api/v1/classes/:id/tickets/:ticket/:action",
+            post(api::update_ticket),
+        )
+        .route(
+            "/api/v1/classes/:id/tickets/:ticket/notes",
+            post(api::add_note),
+        )
+        .route(
+            "/api/v1/classes/:id/tickets/:ticket/messages",
+            post(api::post_message),
+        )
         // handlers for creating/joining classes
-        .route("/create-class", get(class::create))
+        .route("/create-class", get(class::create_form).post(class::create))
         .route("/join-class", get(class::join_form))
         .route("/join-class", post(class::join_submit))
+        // admin link, grants access to the teacher's view
+        .route("/class/:id/admin", get(class::admin))
         // handler for list of open tickets
         .route("/class/:id/teacher", get(teacher::ticket_list))
+        .route("/class/:id/close", post(teacher::close_class))
+        .route("/class/:id/categories", post(teacher::set_categories))
+        .route("/class/:id/policy", post(teacher::set_policy))
+        .route("/class/:id/duplicates", post(teacher::set_duplicates))
+        // downloading every ticket in the class, and statistics about them
+        .route("/class/:id/export", get(export::export))
+        .route("/class/:id/stats", get(stats::view))
+        // live updates to the list of tickets
+        .route("/class/:id/events", get(events::stream))
+        // managing the class's staff, and joining via invite links
+        .route("/class/:id/staff/invite", post(staff::invite))
+        .route("/class/:id/staff/revoke", post(staff::revoke))
+        .route("/class/:id/staff/remove", post(staff::remove))
+        .route("/class/:id/staff/rename", post(staff::rename))
+        .route(
+            "/class/:id/invite/:token",
+            get(staff::invite_form).post(staff::accept_invite),
+        )
         // subscribe for push notifications
         .route("/class/:id/register", post(class::register))
+        .route("/class/:id/unsubscribe", post(class::unsubscribe))
         // handlers for entering and submitting tickets
         .route("/class/:id/student", get(student::view))
         .route("/class/:id/student", post(student::submit_ticket))
+        // handlers for students following the status of their ticket
+        .route("/class/:id/ticket/:ticket", get(student::ticket_view))
+        .route(
+            "/class/:id/ticket/:ticket/events",
+            get(events::ticket_stream),
+        )
+        .route("/class/:id/ticket/:ticket/edit", post(student::edit_ticket))
+        .route(
+            "/class/:id/ticket/:ticket/withdraw",
+            post(student::withdraw_ticket),
+        )
+        .route(
+            "/class/:id/ticket/:ticket/message",
+            post(student::post_message),
+        )
+        // staff viewing the full history of a ticket, taking private notes on it and replying to the student
+        .route("/class/:id/ticket/:ticket/history", get(teacher::history))
+        .route("/class/:id/ticket/:ticket/note", post(teacher::add_note))
+        .route("/class/:id/ticket/:ticket/reply", post(teacher::reply))
+        .route("/class/:id/ticket/:ticket/:action", post(teacher::perform))
         // static data (js and stylesheets)
-        .nest_service("/static", ServeDir::new("static"))
+        .route("/static/:file", assets::route(config.static_dir.clone()))
+        // record how long each request takes
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track,
+        ))
         // state containing classes and their lists of tickets
-        .with_state(state::AppState::init());
+        .with_state(state.clone());
+
+    // mount the app under its base path, if it has one
+    let app = if base_path.is_empty() {
+        app
+    } else {
+        // the index page is usually linked to with a trailing slash, e.g. `https://school.example/summoner/`
+        Router::new()
+            .route(&format!("{base_path}/"), get(root))
+            .nest(&base_path, app)
+    };
 
     // middleware to insert JS to auto-reload page on request
     // from server
@@ -79,29 +283,95 @@ async fn main() -> anyhow::Result<()> {
         app
     };
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], args.port));
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await?;
+    // client addresses are needed to rate-limit class code lookups
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    let addr = SocketAddr::new(config.bind, port);
+
+    // stop accepting connections when asked to, giving open requests (but not event streams, which never
+    // finish) a moment to complete
+    let (handle, redirect_handle) = (Handle::new(), Handle::new());
+    tokio::spawn({
+        let handles = [handle.clone(), redirect_handle.clone()];
+
+        async move {
+            shutdown_signal().await;
+            tracing::info!("shutting down");
+
+            for handle in handles {
+                handle.graceful_shutdown(Some(SHUTDOWN_GRACE));
+            }
+        }
+    });
+
+    if let Some((cert, key)) = config.tls() {
+        let tls = tls::load(cert, key).await?;
+        tls::watch(tls.clone(), cert.to_path_buf(), key.to_path_buf());
+
+        let https = async {
+            axum_server::bind_rustls(addr, tls)
+                .handle(handle)
+                .serve(app)
+                .await?;
+            anyhow::Ok(())
+        };
+
+        // optionally redirect plain HTTP requests, so users don't need to type `https://`
+        let http = async {
+            if let Some(redirect) = config.tls.redirect_port {
+                let addr = SocketAddr::new(config.bind, redirect);
+                axum_server::bind(addr)
+                    .handle(redirect_handle)
+                    .serve(tls::redirect(port).into_make_service())
+                    .await?;
+            }
+            anyhow::Ok(())
+        };
+
+        tokio::try_join!(https, http)?;
+    } else {
+        axum_server::bind(addr).handle(handle).serve(app).await?;
+    }
+
+    // write any changes the background writer hasn't got to yet, so they aren't lost
+    state.flush().await;
+    tracing::info!("saved every class, exiting");
 
     Ok(())
 }
 
+/// Wait for the server to be asked to stop, by Ctrl+C or (on unix) `SIGTERM`
+async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for Ctrl+C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = interrupt => {}
+        () = terminate => {}
+    }
+}
+
 #[derive(Debug, Serialize)]
 struct VapidKey {
-    vapid_key: String, 
+    vapid_key: String,
 }
 
 /// Returns a JSON object containing the server's VAPID public key
 async fn vapid(State(state): State<AppState>) -> axum::Json<VapidKey> {
     axum::Json(VapidKey {
-        vapid_key: Base64UrlUnpadded::encode_string(
-            &state
-                .vapid()
-                .key_pair()
-                .public_key()
-                .to_bytes_uncompressed(),
-        ),
+        vapid_key: vapid::public_key(state.vapid()),
     })
 }
 
@@ -117,9 +387,9 @@ async fn root() -> maud::Markup {
             }
             p {
                 "Please either "
-                a href="/create-class" { "create a class" }
+                a href=(ui::url("/create-class")) { "create a class" }
                 " or "
-                a href="/join-class" { "join a class" }
+                a href=(ui::url("/join-class")) { "join a class" }
                 " to get started!"
             }
         },
@@ -0,0 +1,267 @@
+//! This module collects metrics about how busy the server is, and exposes them for Prometheus to scrape.
+//!
+//! This module is used to define the following endpoints:
+//!   * *GET* `/metrics` ([`metrics`])
+//!
+//! Gauges are gathered from [`AppState`] whenever they are scraped, so they are always consistent with the
+//! classes in memory. Tickets, push deliveries and partial page requests are counted as they happen, and the
+//! latency of every HTTP request is recorded by the [`track`] middleware. A class's counters are removed
+//! when it closes.
+//!
+//! Class codes are used as labels, and anybody with a code can join its class, so metrics are only served
+//! to clients on the same machine unless a token is configured (see `[metrics]` in [`Config`]), in which
+//! case the scraper must send it as a bearer token. Behind a reverse proxy every request appears to come from
+//! the proxy, so without a token, requests carrying forwarding headers are refused, and metrics aren't served
+//! at all if the server is configured as being behind a proxy (with `base_path` or `trusted_proxies`).
+//!
+//! [`Config`]: crate::config::Config
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use axum::extract::{ConnectInfo, MatchedPath, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::auth;
+use crate::state::{AppState, ClassCode};
+
+/// Headers that proxies add to requests they forward
+const FORWARDED_HEADERS: [&str; 2] = ["forwarded", "x-forwarded-for"];
+
+/// Methods that are given their own label; any other method is labelled `other`, so that clients can't create
+/// new series by inventing methods
+const METHODS: [Method; 7] = [
+    Method::GET,
+    Method::HEAD,
+    Method::POST,
+    Method::PUT,
+    Method::PATCH,
+    Method::DELETE,
+    Method::OPTIONS,
+];
+
+/// The metrics for the server, registered with their own registry
+pub struct Metrics {
+    registry: Registry,
+    /// The bearer token scrapers must provide, if any
+    token: Option<String>,
+    /// Whether the server is behind a reverse proxy, so that requests which appear local may not be
+    behind_proxy: bool,
+
+    classes: IntGauge,
+    open_tickets: IntGaugeVec,
+    tickets_created: IntCounterVec,
+    tickets_resolved: IntCounterVec,
+    event_streams: IntGaugeVec,
+    partial_requests: IntCounterVec,
+    push_sent: IntCounter,
+    push_failed: IntCounter,
+    request_duration: HistogramVec,
+}
+
+impl Metrics {
+    /// Create and register every metric. Scrapers must provide the given token, if there is one.
+    pub fn new(token: Option<String>, behind_proxy: bool) -> Metrics {
+        let registry = Registry::new_custom(Some("summoner".to_string()), None).unwrap();
+
+        /// Register a metric, returning it. Registering only fails for duplicate or invalid names.
+        fn register<M: prometheus::core::Collector + Clone + 'static>(
+            registry: &Registry,
+            metric: M,
+        ) -> M {
+            registry.register(Box::new(metric.clone())).unwrap();
+            metric
+        }
+
+        let class = &["class"];
+
+        Metrics {
+            classes: register(&registry, IntGauge::new("classes", "Classes that are open").unwrap()),
+            open_tickets: register(
+                &registry,
+                IntGaugeVec::new(Opts::new("open_tickets", "Tickets that are still open"), class).unwrap(),
+            ),
+            tickets_created: register(
+                &registry,
+                IntCounterVec::new(Opts::new("tickets_created_total", "Tickets opened by students"), class)
+                    .unwrap(),
+            ),
+            tickets_resolved: register(
+                &registry,
+                IntCounterVec::new(Opts::new("tickets_resolved_total", "Tickets resolved by staff"), class)
+                    .unwrap(),
+            ),
+            event_streams: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("event_streams", "Clients connected to a class's live event stream"),
+                    class,
+                )
+                .unwrap(),
+            ),
+            partial_requests: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "partial_requests_total",
+                        "Requests for part of a page, by clients polling or catching up after an event",
+                    ),
+                    &["view"],
+                )
+                .unwrap(),
+            ),
+            push_sent: register(
+                &registry,
+                IntCounter::new("push_sent_total", "Push notifications delivered").unwrap(),
+            ),
+            push_failed: register(
+                &registry,
+                IntCounter::new("push_failed_total", "Push notifications that could not be delivered").unwrap(),
+            ),
+            request_duration: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new("http_request_duration_seconds", "Time taken to respond to requests"),
+                    &["method", "route", "status"],
+                )
+                .unwrap(),
+            ),
+            registry,
+            token,
+            behind_proxy,
+        }
+    }
+
+    /// Record the outcome of sending a push notification
+    pub fn record_push(&self, delivered: bool) {
+        if delivered {
+            self.push_sent.inc();
+        } else {
+            self.push_failed.inc();
+        }
+    }
+
+    /// Record a student opening a ticket in a class
+    pub fn record_created(&self, code: ClassCode) {
+        self.tickets_created
+            .with_label_values(&[code.as_str()])
+            .inc();
+    }
+
+    /// Record a member of staff resolving a ticket in a class
+    pub fn record_resolved(&self, code: ClassCode) {
+        self.tickets_resolved
+            .with_label_values(&[code.as_str()])
+            .inc();
+    }
+
+    /// Stop reporting the counters of a class that has been closed
+    pub fn remove_class(&self, code: ClassCode) {
+        // the class may not have had any tickets, in which case it has no counters
+        let _ = self.tickets_created.remove_label_values(&[code.as_str()]);
+        let _ = self.tickets_resolved.remove_label_values(&[code.as_str()]);
+    }
+
+    /// Record a request for part of a page (e.g. `teacher` for the list of tickets)
+    pub fn record_partial(&self, view: &str) {
+        self.partial_requests.with_label_values(&[view]).inc();
+    }
+
+    /// Record the time taken to respond to a request
+    fn record_request(&self, method: &str, route: &str, status: StatusCode, elapsed: Duration) {
+        self.request_duration
+            .with_label_values(&[method, route, status.as_str()])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Update the metrics gathered from the state, and encode every metric in the Prometheus text format
+    fn encode(&self, state: &AppState) -> String {
+        let activity = state.activity();
+
+        // classes that have been closed shouldn't be reported any more
+        self.open_tickets.reset();
+        self.event_streams.reset();
+
+        self.classes.set(activity.len() as i64);
+
+        for class in activity {
+            let code = [class.code.as_str()];
+
+            self.open_tickets
+                .with_label_values(&code)
+                .set(class.open as i64);
+            self.event_streams
+                .with_label_values(&code)
+                .set(class.listeners as i64);
+        }
+
+        let mut buf = vec![];
+        // encoding to a `Vec` can only fail for invalid metrics, which are rejected when registering them
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .unwrap();
+
+        String::from_utf8(buf).unwrap()
+    }
+}
+
+/// Handler for Prometheus to scrape the server's metrics
+pub async fn metrics(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    let metrics = state.metrics();
+
+    let allowed = match &metrics.token {
+        Some(token) => {
+            auth::get_bearer(&headers).is_some_and(|given| auth::secret_eq(token, given))
+        }
+        // a request forwarded by a proxy on the same machine would otherwise appear to be local
+        None => {
+            let forwarded = FORWARDED_HEADERS.iter().any(|h| headers.contains_key(*h));
+            !metrics.behind_proxy && !forwarded && addr.ip().is_loopback()
+        }
+    };
+
+    if !allowed {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    (
+        [(CONTENT_TYPE, TextEncoder::new().format_type().to_string())],
+        metrics.encode(&state),
+    )
+        .into_response()
+}
+
+/// Middleware recording how long each request takes to respond to, by the route it matched
+pub async fn track<B>(State(state): State<AppState>, req: Request<B>, next: Next<B>) -> Response {
+    let start = Instant::now();
+    let method = match req.method() {
+        method if METHODS.contains(method) => method.as_str().to_string(),
+        _ => "other".to_string(),
+    };
+
+    // label by the route's pattern rather than the path, so each class doesn't get its own set of metrics
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let resp = next.run(req).await;
+    state
+        .metrics()
+        .record_request(&method, &route, resp.status(), start.elapsed());
+
+    resp
+}
@@ -0,0 +1,344 @@
+//! This module contains the logic for delivering Web Push notifications to subscribed teachers. No
+//! endpoints are defined in this module.
+//!
+//! Notifications are encrypted and signed with the server's VAPID key via [`WebPushBuilder`], and then
+//! sent to the subscriber's push service using `reqwest`. The JSON payload is read by
+//! `static/service-worker.js`, which expects a `title` and a `body` field.
+//!
+//! A class may have any number of [`Subscriber`]s (e.g. the teacher's laptop and phone). Each is notified
+//! independently; subscribers that the push service reports as gone are removed from the class.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+use web_push_native::WebPushBuilder;
+
+use crate::state::{AppState, ClassCode};
+
+/// The longest a device's label may be, in characters
+pub const MAX_LABEL_LEN: usize = 40;
+
+/// How long to wait for a push service to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for a push service to respond to a notification, including connecting to it
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Error type for failures when delivering a push notification
+#[derive(thiserror::Error, Debug)]
+pub enum PushError {
+    /// Failed to encrypt or sign the notification
+    #[error("failed to build push message: {0}")]
+    Build(#[from] web_push_native::Error),
+    /// Failed to convert or send the request to the push service
+    #[error("failed to send push message: {0}")]
+    Send(#[from] reqwest::Error),
+    /// The push service rejected the notification
+    #[error("push service responded with {0}")]
+    Status(reqwest::StatusCode),
+}
+
+impl PushError {
+    /// Returns `true` if the push service reports that the subscription no longer exists, in which case
+    /// it should not be used again
+    pub fn is_gone(&self) -> bool {
+        matches!(
+            self,
+            PushError::Status(reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE)
+        )
+    }
+}
+
+/// A device subscribed to push notifications for a class
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscriber {
+    /// The subscription, as provided by the browser
+    subscription: WebPushBuilder,
+    /// The URL of the subscription at the push service, which uniquely identifies the subscriber
+    endpoint: String,
+    /// A name for the device, chosen by the teacher
+    label: String,
+    /// When the device subscribed
+    created: DateTime<Utc>,
+    /// The last time a notification was successfully delivered to the device
+    last_success: Option<DateTime<Utc>>,
+}
+
+impl Subscriber {
+    /// Create a new subscriber with a given label
+    pub fn new(subscription: WebPushBuilder, label: &str) -> Subscriber {
+        // the builder doesn't expose its endpoint, but does include it when serialised
+        let endpoint = serde_json::to_value(&subscription)
+            .ok()
+            .and_then(|v| v["endpoint"].as_str().map(str::to_string))
+            .unwrap_or_default();
+
+        Subscriber {
+            subscription,
+            endpoint,
+            label: label.trim().chars().take(MAX_LABEL_LEN).collect(),
+            created: Utc::now(),
+            last_success: None,
+        }
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn created(&self) -> DateTime<Utc> {
+        self.created
+    }
+
+    pub fn last_success(&self) -> Option<DateTime<Utc>> {
+        self.last_success
+    }
+
+    /// Record that a notification was delivered to the device
+    pub fn succeeded(&mut self) {
+        self.last_success = Some(Utc::now());
+    }
+}
+
+/// Deserialise a class's subscribers. Classes saved before multiple subscribers were supported have a
+/// single (optional) subscription instead, which is converted to a list.
+pub fn deserialize_subscribers<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Subscriber>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        List(Vec<Subscriber>),
+        Single(Option<WebPushBuilder>),
+    }
+
+    Ok(match Stored::deserialize(deserializer)? {
+        Stored::List(subs) => subs,
+        Stored::Single(sub) => sub
+            .map(|sub| Subscriber::new(sub, "Unnamed device"))
+            .into_iter()
+            .collect(),
+    })
+}
+
+/// The payload of a notification, as expected by the service worker
+#[derive(Debug, Serialize)]
+pub struct Notification {
+    /// Title of the notification
+    pub title: String,
+    /// Body text of the notification
+    pub body: String,
+}
+
+/// Create the HTTP client used for delivering notifications. Requests time out, so that a push service that
+/// stops responding doesn't leave a task waiting on it for each notification sent.
+pub fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(TIMEOUT)
+        .build()
+        // this only fails if the TLS backend can't be initialised, which `reqwest::Client::new` panics on too
+        .expect("failed to create HTTP client")
+}
+
+/// Encrypt and send a notification to a given subscriber
+pub async fn send(
+    state: &AppState,
+    sub: &WebPushBuilder,
+    msg: &Notification,
+) -> Result<(), PushError> {
+    // serialising a struct of strings can never fail
+    let payload = serde_json::to_vec(msg).expect("failed to serialise notification");
+
+    // sign the request with our VAPID key, and encrypt the payload for the subscriber
+    let req = sub
+        .clone()
+        .with_vapid(state.vapid(), state.vapid_subject())
+        .build(payload)?;
+
+    // convert the `http` request into one `reqwest` can send
+    let req = reqwest::Request::try_from(req)?;
+    let resp = state.http().execute(req).await?;
+
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(PushError::Status(resp.status()))
+    }
+}
+
+/// Send a notification to every subscriber of a class in the background. Any failures are logged, rather
+/// than reported to the caller, and subscribers that no longer exist are removed.
+pub fn notify(state: &AppState, code: ClassCode, msg: Notification) {
+    // a class that has just been closed has nobody left to notify
+    let subs = state
+        .with_tickets(code, |t| t.subscribers().to_vec())
+        .unwrap_or_default();
+    let msg = std::sync::Arc::new(msg);
+
+    for sub in subs {
+        let (state, msg) = (state.clone(), msg.clone());
+
+        tokio::spawn(async move {
+            let endpoint = sub.endpoint();
+
+            let result = send(&state, &sub.subscription, &msg).await;
+            state.metrics().record_push(result.is_ok());
+
+            match result {
+                Ok(()) => {
+                    tracing::debug!(%code, label = sub.label, "push notification sent");
+                    // the class may have been closed since the notification was sent
+                    state.update_class(code, |t| t.push_succeeded(endpoint));
+                }
+                Err(e) if e.is_gone() => {
+                    tracing::info!(%code, label = sub.label, "push subscription expired, removing");
+                    state.update_class(code, |t| t.unsubscribe(endpoint));
+                }
+                Err(e) => {
+                    tracing::warn!(%code, label = sub.label, error = %e, "failed to send push notification")
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use axum::extract::Path;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::Router;
+
+    use web_push_native::jwt_simple::algorithms::{ECDSAP256KeyPairLike, ES256KeyPair};
+    use web_push_native::{p256, Auth};
+
+    use super::*;
+    use crate::metrics::Metrics;
+    use crate::ratelimit::RateLimiter;
+    use crate::state::CodeFormat;
+    use crate::storage::MemoryStorage;
+
+    /// Start a stand-in push service, which responds to `POST /:status` with that status
+    fn push_service() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let app = Router::new().route(
+            "/:status",
+            post(|Path(status): Path<u16>| async move { StatusCode::from_u16(status).unwrap() }),
+        );
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+
+        addr
+    }
+
+    fn state() -> AppState {
+        AppState::init(
+            Box::new(MemoryStorage),
+            ES256KeyPair::generate(),
+            "mailto:test@example.com".to_string(),
+            CodeFormat::new(6, CodeFormat::DEFAULT_ALPHABET).unwrap(),
+            RateLimiter::new(10, Duration::from_secs(60), vec![]),
+            Metrics::new(None, false),
+        )
+        .unwrap()
+    }
+
+    /// A subscription whose push service responds with the given status
+    fn subscription(service: SocketAddr, status: u16) -> WebPushBuilder {
+        // any P-256 key will do, as the stand-in never decrypts the payload
+        let key = ES256KeyPair::generate()
+            .key_pair()
+            .public_key()
+            .to_bytes_uncompressed();
+        let key = p256::PublicKey::from_sec1_bytes(&key).unwrap();
+
+        WebPushBuilder::new(
+            format!("http://{service}/{status}").parse().unwrap(),
+            key,
+            Auth::default(),
+        )
+    }
+
+    fn notification() -> Notification {
+        Notification {
+            title: "Alice needs help".to_string(),
+            body: "stuck on part 2".to_string(),
+        }
+    }
+
+    #[test]
+    fn labels_are_truncated() {
+        let label = format!(" {} ", "x".repeat(MAX_LABEL_LEN + 10));
+        let sub = Subscriber::new(subscription("127.0.0.1:1".parse().unwrap(), 201), &label);
+
+        assert_eq!(sub.label(), "x".repeat(MAX_LABEL_LEN));
+    }
+
+    #[tokio::test]
+    async fn send_reports_status() {
+        let (state, service) = (state(), push_service());
+
+        assert!(send(&state, &subscription(service, 201), &notification())
+            .await
+            .is_ok());
+
+        for (status, gone) in [(404, true), (410, true), (429, false), (500, false)] {
+            let err = send(&state, &subscription(service, status), &notification())
+                .await
+                .unwrap_err();
+
+            assert!(matches!(err, PushError::Status(s) if s.as_u16() == status));
+            assert_eq!(err.is_gone(), gone, "status {status}");
+        }
+    }
+
+    #[tokio::test]
+    async fn notify_removes_gone_subscribers() {
+        let (state, service) = (state(), push_service());
+        let code = state.create_class().unwrap();
+
+        for (status, label) in [(201, "laptop"), (410, "old phone"), (500, "tablet")] {
+            let sub = Subscriber::new(subscription(service, status), label);
+            state
+                .with_tickets_mut(code, |t| t.subscribe(sub.clone()))
+                .unwrap();
+        }
+
+        notify(&state, code, notification());
+
+        // notifications are sent in the background
+        let labels = || {
+            state
+                .with_tickets(code, |t| {
+                    t.subscribers()
+                        .iter()
+                        .map(|s| s.label().to_string())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap()
+        };
+        for _ in 0..100 {
+            if labels().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        assert_eq!(labels(), ["laptop", "tablet"]);
+    }
+}
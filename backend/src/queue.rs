@@ -0,0 +1,246 @@
+//! This module contains the policies that decide the order a class's tickets are helped in. No endpoints
+//! are defined in this module.
+//!
+//! Each policy implements [`QueuePolicy`], and is chosen per class via [`Policy`]. The order is used both
+//! for the teacher's list of tickets, and for the queue positions shown to students.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use chrono::Local;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ticket::{normalise_name, Status, Ticket, TicketList};
+
+/// Decides the order that open tickets should be helped in
+pub trait QueuePolicy {
+    /// Sort a class's open tickets, so that the first ticket is the next to be helped
+    fn sort(&self, list: &TicketList, tickets: &mut [&Ticket]);
+}
+
+/// Tickets are helped in the order they were opened
+pub struct Fifo;
+
+impl QueuePolicy for Fifo {
+    fn sort(&self, _: &TicketList, tickets: &mut [&Ticket]) {
+        tickets.sort_by_key(|t| t.id());
+    }
+}
+
+/// Tickets the teacher has bumped are helped first, in the order they were bumped, followed by every other
+/// ticket in the order they were opened
+pub struct PriorityFirst;
+
+impl QueuePolicy for PriorityFirst {
+    fn sort(&self, _: &TicketList, tickets: &mut [&Ticket]) {
+        tickets.sort_by_key(|t| (t.bumped().is_none(), t.bumped(), t.id()));
+    }
+}
+
+/// Students that have been helped fewer times today are helped first, so that one student can't monopolise
+/// the teacher. Students are identified by their (normalised) name, and ties are broken by the order tickets
+/// were opened.
+pub struct RoundRobin;
+
+impl QueuePolicy for RoundRobin {
+    fn sort(&self, list: &TicketList, tickets: &mut [&Ticket]) {
+        let today = Local::now().date_naive();
+
+        // count the tickets resolved today for each student
+        let mut helped: HashMap<String, usize> = HashMap::new();
+        for ticket in list.tickets() {
+            let resolved_today = ticket
+                .time_of(Status::Resolved)
+                .map(|at| at.with_timezone(&Local).date_naive() == today)
+                .unwrap_or(false);
+
+            if resolved_today {
+                *helped.entry(normalise_name(ticket.student())).or_default() += 1;
+            }
+        }
+
+        tickets.sort_by_key(|t| {
+            let count = helped
+                .get(&normalise_name(t.student()))
+                .copied()
+                .unwrap_or(0);
+            (count, t.id())
+        });
+    }
+}
+
+/// Tickets in the class's earlier categories are treated as if they were opened earlier, so are helped
+/// sooner without starving the later categories. Uncategorised tickets, and those in categories that have
+/// since been removed, are given no head start.
+pub struct CategoryWeighted;
+
+impl CategoryWeighted {
+    /// How much earlier a ticket is treated as being opened, for each category it is ahead of the last
+    const HEAD_START_MINS: i64 = 5;
+}
+
+impl QueuePolicy for CategoryWeighted {
+    fn sort(&self, list: &TicketList, tickets: &mut [&Ticket]) {
+        let categories = list.categories();
+
+        tickets.sort_by_key(|t| {
+            // the first category has the largest weight, and the last has a weight of 0
+            let weight = t
+                .category()
+                .and_then(|c| categories.iter().position(|cat| cat == c))
+                .map(|i| categories.len() - 1 - i)
+                .unwrap_or(0);
+
+            let head_start = chrono::Duration::minutes(Self::HEAD_START_MINS * weight as i64);
+            (t.created() - head_start, t.id())
+        });
+    }
+}
+
+/// The queue policy used by a class
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    /// See [`Fifo`]
+    #[default]
+    Fifo,
+    /// See [`PriorityFirst`]
+    Priority,
+    /// See [`RoundRobin`]
+    RoundRobin,
+    /// See [`CategoryWeighted`]
+    CategoryWeighted,
+}
+
+impl Policy {
+    /// Every policy, in the order they are presented to the teacher
+    pub const ALL: [Policy; 4] = [
+        Policy::Fifo,
+        Policy::Priority,
+        Policy::RoundRobin,
+        Policy::CategoryWeighted,
+    ];
+
+    /// Returns the implementation of the policy
+    pub fn implementation(&self) -> &'static dyn QueuePolicy {
+        match self {
+            Policy::Fifo => &Fifo,
+            Policy::Priority => &PriorityFirst,
+            Policy::RoundRobin => &RoundRobin,
+            Policy::CategoryWeighted => &CategoryWeighted,
+        }
+    }
+
+    /// The name of the policy, as used in forms and the JSON API
+    pub fn name(&self) -> &'static str {
+        match self {
+            Policy::Fifo => "fifo",
+            Policy::Priority => "priority",
+            Policy::RoundRobin => "round_robin",
+            Policy::CategoryWeighted => "category_weighted",
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Policy::Fifo => "first come, first served",
+            Policy::Priority => "bumped tickets first",
+            Policy::RoundRobin => "least helped students first",
+            Policy::CategoryWeighted => "earlier categories first",
+        };
+
+        write!(fmt, "{name}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ticket::{Actor, TicketId};
+
+    /// Open a ticket for each student, in order, with the given category
+    fn open(list: &mut TicketList, tickets: &[(&str, Option<&str>)]) -> Vec<TicketId> {
+        tickets
+            .iter()
+            .map(|(student, category)| {
+                list.add_ticket(student, None::<&str>, *category, None)
+                    .unwrap()
+                    .id()
+            })
+            .collect()
+    }
+
+    /// The students in the order their tickets will be helped
+    fn order(list: &TicketList) -> Vec<&str> {
+        list.queue().iter().map(|t| t.student()).collect()
+    }
+
+    #[test]
+    fn fifo_helps_in_order_opened() {
+        let mut list = TicketList::new();
+        let ids = open(
+            &mut list,
+            &[("Alice", None), ("Bob", None), ("Carol", None)],
+        );
+        list.bump(ids[2], true).unwrap();
+
+        assert_eq!(order(&list), ["Alice", "Bob", "Carol"]);
+    }
+
+    #[test]
+    fn priority_helps_bumped_first() {
+        let mut list = TicketList::new();
+        list.set_policy(Policy::Priority);
+        let ids = open(
+            &mut list,
+            &[("Alice", None), ("Bob", None), ("Carol", None)],
+        );
+
+        list.bump(ids[2], true).unwrap();
+        // ensure the bumps have distinct times
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        list.bump(ids[1], true).unwrap();
+        assert_eq!(order(&list), ["Carol", "Bob", "Alice"]);
+
+        list.bump(ids[2], false).unwrap();
+        assert_eq!(order(&list), ["Bob", "Alice", "Carol"]);
+    }
+
+    #[test]
+    fn round_robin_helps_least_helped_first() {
+        let mut list = TicketList::new();
+        list.set_policy(Policy::RoundRobin);
+
+        let ids = open(&mut list, &[("Alice", None)]);
+        list.transition(ids[0], Status::Resolved, Actor::Teacher, None::<&str>)
+            .unwrap();
+
+        // names are compared once normalised
+        open(&mut list, &[(" alice ", None), ("Bob", None)]);
+        assert_eq!(order(&list), ["Bob", "alice"]);
+    }
+
+    #[test]
+    fn category_weighted_helps_earlier_categories_first() {
+        let mut list = TicketList::new();
+        list.set_policy(Policy::CategoryWeighted);
+        list.set_categories(["Bug", "Question", "Other"]);
+
+        open(
+            &mut list,
+            &[
+                ("Alice", Some("Other")),
+                ("Bob", Some("Question")),
+                ("Carol", Some("Bug")),
+            ],
+        );
+        assert_eq!(order(&list), ["Carol", "Bob", "Alice"]);
+
+        // tickets in removed categories get no head start
+        list.set_categories(["Question", "Other"]);
+        assert_eq!(order(&list), ["Bob", "Alice", "Carol"]);
+    }
+}
@@ -0,0 +1,288 @@
+//! This module contains a rate limiter for failed class code lookups. No endpoints are defined in this module.
+//!
+//! Each IP address may only enter a limited number of unknown class codes within a time window. Once the
+//! limit is reached, further lookups from that address are refused until older failures fall out of the
+//! window, and an entry is written to the `audit` log target, as the user may be trying to enumerate codes.
+//!
+//! Behind a reverse proxy every request comes from the proxy's address, so requests from the proxies listed in
+//! `trusted_proxies` (see [`Config`]) are attributed to the client named in their `X-Forwarded-For` header
+//! instead. Handlers find this address with the [`ClientIp`] extractor.
+//!
+//! [`Config`]: crate::config::Config
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::async_trait;
+use axum::extract::rejection::ExtensionRejection;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::request::Parts;
+use axum::http::HeaderMap;
+
+use crate::state::AppState;
+
+/// The header proxies add the address they received a request from to
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Tracks failed lookups per IP address
+pub struct RateLimiter {
+    /// The number of failures allowed within `window`. If `0`, lookups are never limited
+    max_failures: usize,
+    /// The length of time failures are remembered for
+    window: Duration,
+    /// The times of recent failures, for each address
+    failures: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+    /// Reverse proxies whose `X-Forwarded-For` header is trusted to give the client's address
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl RateLimiter {
+    /// Create a rate limiter allowing `max_failures` within a given `window`. Requests from the given proxies
+    /// are attributed to the client they were forwarded for.
+    pub fn new(max_failures: usize, window: Duration, trusted_proxies: Vec<IpAddr>) -> RateLimiter {
+        RateLimiter {
+            max_failures,
+            window,
+            failures: Mutex::new(HashMap::new()),
+            trusted_proxies,
+        }
+    }
+
+    /// Find the address of the client that sent a request, which reached us from `peer`. Each proxy appends
+    /// the address it received the request from to `X-Forwarded-For`, so the header is read from the end,
+    /// stopping at the first address that isn't a trusted proxy (anything before it may have been forged).
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let forwarded: Vec<_> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .collect();
+
+        let mut client = peer;
+        for addr in forwarded.into_iter().rev() {
+            if !self.trusted_proxies.contains(&client) {
+                break;
+            }
+
+            match addr.trim().parse() {
+                Ok(addr) => client = addr,
+                // the proxy was given a malformed header, so the best we know is the proxy itself
+                Err(_) => break,
+            }
+        }
+
+        client
+    }
+
+    /// Returns `true` if the address has failed too many lookups recently
+    pub fn is_limited(&self, ip: IpAddr) -> bool {
+        if self.max_failures == 0 {
+            return false;
+        }
+
+        let cutoff = Instant::now() - self.window;
+        let failures = self.failures.lock().unwrap();
+
+        failures
+            .get(&ip)
+            .map(|f| f.iter().filter(|t| **t > cutoff).count() >= self.max_failures)
+            .unwrap_or(false)
+    }
+
+    /// Record a failed lookup from an address
+    pub fn record_failure(&self, ip: IpAddr) {
+        if self.max_failures == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let cutoff = now - self.window;
+        let mut failures = self.failures.lock().unwrap();
+
+        // forget failures that have fallen out of the window, so the map doesn't grow forever
+        failures.retain(|_, f| {
+            while f.front().map(|t| *t <= cutoff).unwrap_or(false) {
+                f.pop_front();
+            }
+
+            !f.is_empty()
+        });
+
+        let recent = failures.entry(ip).or_default();
+        recent.push_back(now);
+
+        if recent.len() == self.max_failures {
+            // only log when the limit is first reached, to avoid flooding the log
+            tracing::warn!(
+                target: "audit",
+                %ip,
+                failures = recent.len(),
+                window_secs = self.window.as_secs(),
+                "possible class code enumeration, rate limiting address"
+            );
+        }
+    }
+}
+
+/// Extractor for the address of the client that sent a request, taking trusted proxies into account (see
+/// [`RateLimiter::client_ip`])
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = ExtensionRejection;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<ClientIp, ExtensionRejection> {
+        let ConnectInfo(peer) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state).await?;
+
+        Ok(ClientIp(state.client_ip(peer.ip(), &parts.headers)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    fn forwarded(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(X_FORWARDED_FOR, value.parse().unwrap());
+        }
+
+        headers
+    }
+
+    #[test]
+    fn client_ip_trusts_only_listed_proxies() {
+        let limiter = RateLimiter::new(
+            10,
+            Duration::from_secs(60),
+            vec![ip("10.0.0.1"), ip("10.0.0.2")],
+        );
+        let headers = forwarded(&["198.51.100.7"]);
+
+        // an untrusted peer's header could be forged
+        assert_eq!(
+            limiter.client_ip(ip("203.0.113.1"), &headers),
+            ip("203.0.113.1")
+        );
+        assert_eq!(
+            limiter.client_ip(ip("10.0.0.1"), &headers),
+            ip("198.51.100.7")
+        );
+        // a trusted proxy that didn't forward the request
+        assert_eq!(
+            limiter.client_ip(ip("10.0.0.1"), &HeaderMap::new()),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn client_ip_walks_hops_from_the_right() {
+        let limiter = RateLimiter::new(
+            10,
+            Duration::from_secs(60),
+            vec![ip("10.0.0.1"), ip("10.0.0.2")],
+        );
+
+        // the client forged the first address, then the request passed through both proxies
+        let headers = forwarded(&["192.0.2.1, 198.51.100.7", "10.0.0.2"]);
+        assert_eq!(
+            limiter.client_ip(ip("10.0.0.1"), &headers),
+            ip("198.51.100.7")
+        );
+
+        // every hop is trusted, so the leftmost address is the client
+        let headers = forwarded(&["10.0.0.2, 10.0.0.1"]);
+        assert_eq!(limiter.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn client_ip_stops_at_malformed_addresses() {
+        let limiter = RateLimiter::new(
+            10,
+            Duration::from_secs(60),
+            vec![ip("10.0.0.1"), ip("10.0.0.2")],
+        );
+
+        let headers = forwarded(&["198.51.100.7, not-an-address"]);
+        assert_eq!(limiter.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.1"));
+
+        let headers = forwarded(&["198.51.100.7, junk, 10.0.0.2"]);
+        assert_eq!(limiter.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn failures_expire_after_the_window() {
+        let limiter = RateLimiter::new(2, Duration::from_millis(100), vec![]);
+        let (client, other) = (ip("198.51.100.7"), ip("198.51.100.8"));
+
+        limiter.record_failure(client);
+        assert!(!limiter.is_limited(client));
+        limiter.record_failure(client);
+        assert!(limiter.is_limited(client));
+        assert!(!limiter.is_limited(other));
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(!limiter.is_limited(client));
+    }
+
+    #[test]
+    fn zero_never_limits() {
+        let limiter = RateLimiter::new(0, Duration::from_secs(60), vec![]);
+
+        for _ in 0..100 {
+            limiter.record_failure(ip("198.51.100.7"));
+        }
+        assert!(!limiter.is_limited(ip("198.51.100.7")));
+    }
+
+    /// Collects everything logged, for checking which events were written
+    #[derive(Clone, Default)]
+    struct Log(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Log {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn audit_log_written_once_limited() {
+        let log = Log::default();
+        let writer = log.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+
+        let limiter = RateLimiter::new(3, Duration::from_secs(60), vec![]);
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..10 {
+                limiter.record_failure(ip("198.51.100.7"));
+            }
+        });
+
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(log.matches("possible class code enumeration").count(), 1);
+        assert!(log.contains("audit") && log.contains("ip=198.51.100.7"));
+    }
+}
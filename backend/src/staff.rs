@@ -0,0 +1,662 @@
+//! This module contains the staff of a class: the owner that created it, and any co-teachers and teaching
+//! assistants they have invited.
+//!
+//! This module is used to define the following endpoints:
+//!   * *POST* `/class/{id}/staff/invite`   ([`invite`])
+//!   * *POST* `/class/{id}/staff/revoke`   ([`revoke`])
+//!   * *POST* `/class/{id}/staff/remove`   ([`remove`])
+//!   * *POST* `/class/{id}/staff/rename`   ([`rename`])
+//!   * *GET*  `/class/{id}/invite/{token}` ([`invite_form`])
+//!   * *POST* `/class/{id}/invite/{token}` ([`accept_invite`])
+//!
+//! The owner invites staff by creating an invite link for a given [`Role`], which can be used once. Whoever
+//! opens the link picks a display name, and is given their own token in the teacher's cookie. Each member's
+//! display name is recorded against the changes they make to tickets. What each role is allowed to do is
+//! decided by [`Role::can`].
+
+use std::fmt;
+use std::net::IpAddr;
+
+use axum::extract::{Form, Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Redirect, Response};
+
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{self, Forbidden, Token};
+use crate::ratelimit::ClientIp;
+use crate::state::{AppState, ClassCode, LookupError, UnknownClass};
+use crate::ui;
+
+/// The longest a member of staff's display name may be, in characters
+pub const MAX_NAME_LEN: usize = 60;
+
+/// The role of a member of staff in a class
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// The teacher that created the class
+    Owner,
+    /// A teacher invited by the owner, who can do anything except manage the class itself
+    CoTeacher,
+    /// A teaching assistant, who can help students but not dismiss their tickets
+    Assistant,
+}
+
+impl Role {
+    /// Returns `true` if staff with this role have a given permission
+    pub fn can(&self, permission: Permission) -> bool {
+        match permission {
+            Permission::ManageTickets => true,
+            Permission::DismissTickets | Permission::ExportTickets => *self != Role::Assistant,
+            Permission::ManageClass => *self == Role::Owner,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Role::Owner => "owner",
+            Role::CoTeacher => "co-teacher",
+            Role::Assistant => "teaching assistant",
+        };
+
+        write!(fmt, "{name}")
+    }
+}
+
+/// Something only some members of staff are allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// View the class's tickets, claim and resolve them, and receive notifications
+    ManageTickets,
+    /// Close a ticket without helping the student
+    DismissTickets,
+    /// Download every ticket in the class, including the staff's private notes
+    ExportTickets,
+    /// Close the class, change its settings, and invite or remove staff
+    ManageClass,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Permission::ManageTickets => "manage tickets",
+            Permission::DismissTickets => "dismiss tickets",
+            Permission::ExportTickets => "export tickets",
+            Permission::ManageClass => "manage the class",
+        };
+
+        write!(fmt, "{name}")
+    }
+}
+
+/// Uniquely identifies a member of staff within a class. The owner is always `0`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct StaffId(usize);
+
+/// A member of staff in a class
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Staff {
+    /// ID of the member
+    id: StaffId,
+    /// The name shown on tickets the member changes
+    name: String,
+    /// What the member is allowed to do
+    role: Role,
+    /// Secret token identifying the member
+    token: Token,
+}
+
+impl Staff {
+    pub fn id(&self) -> StaffId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn token(&self) -> &Token {
+        &self.token
+    }
+
+    /// Check that the member has a given permission
+    pub fn require(&self, permission: Permission) -> Result<(), Forbidden> {
+        if self.role.can(permission) {
+            Ok(())
+        } else {
+            Err(Forbidden::Restricted(self.role, permission))
+        }
+    }
+}
+
+/// An unused invite link
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Invite {
+    /// Secret token, given in the invite link
+    token: Token,
+    /// The role given to whoever accepts the invite
+    role: Role,
+}
+
+/// The staff of a class, other than the owner's token (which is the class's original teacher token)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StaffList {
+    /// The display name of the owner
+    owner_name: String,
+    /// Invited members of staff
+    members: Vec<Staff>,
+    /// Invites that haven't been used yet
+    invites: Vec<Invite>,
+    /// The ID to give to the next member
+    next_id: usize,
+}
+
+impl Default for StaffList {
+    fn default() -> StaffList {
+        StaffList {
+            owner_name: "Teacher".to_string(),
+            members: vec![],
+            invites: vec![],
+            // the owner is always ID `0`
+            next_id: 1,
+        }
+    }
+}
+
+impl StaffList {
+    /// Returns the owner of the class, given the class's teacher token
+    pub fn owner(&self, token: &Token) -> Staff {
+        Staff {
+            id: StaffId(0),
+            name: self.owner_name.clone(),
+            role: Role::Owner,
+            token: token.clone(),
+        }
+    }
+
+    /// Find the invited member of staff with a given token
+    pub fn find(&self, given: &str) -> Option<&Staff> {
+        self.members.iter().find(|m| m.token.matches(given))
+    }
+
+    /// Returns the invited members of staff
+    pub fn members(&self) -> &[Staff] {
+        &self.members
+    }
+
+    /// Create an invite for a given role, returning the invite's token
+    pub fn invite(&mut self, role: Role) -> Token {
+        let token = Token::generate();
+        self.invites.push(Invite {
+            token: token.clone(),
+            role,
+        });
+
+        token
+    }
+
+    /// Returns the tokens of unused invites, along with the role they give
+    pub fn invites(&self) -> impl Iterator<Item = (&Token, Role)> {
+        self.invites.iter().map(|i| (&i.token, i.role))
+    }
+
+    /// Returns the role an invite gives, if it exists
+    pub fn invite_role(&self, given: &str) -> Option<Role> {
+        self.invites
+            .iter()
+            .find(|i| i.token.matches(given))
+            .map(|i| i.role)
+    }
+
+    /// Delete an unused invite. Returns `false` if it didn't exist
+    pub fn revoke(&mut self, given: &str) -> bool {
+        let len = self.invites.len();
+        self.invites.retain(|i| !i.token.matches(given));
+
+        self.invites.len() != len
+    }
+
+    /// Use an invite to add a new member of staff with a given name. The invite can't be used again.
+    pub fn accept(&mut self, given: &str, name: &str) -> Option<Staff> {
+        let role = self.invite_role(given)?;
+        self.revoke(given);
+
+        let member = Staff {
+            id: StaffId(self.next_id),
+            name: display_name(name),
+            role,
+            token: Token::generate(),
+        };
+
+        self.next_id += 1;
+        self.members.push(member.clone());
+
+        Some(member)
+    }
+
+    /// Remove an invited member of staff, so their token no longer works. Returns `false` if they weren't
+    /// a member. The owner can't be removed.
+    pub fn remove(&mut self, id: StaffId) -> bool {
+        let len = self.members.len();
+        self.members.retain(|m| m.id != id);
+
+        self.members.len() != len
+    }
+
+    /// Change the display name of a member of staff (including the owner)
+    pub fn rename(&mut self, id: StaffId, name: &str) {
+        let name = display_name(name);
+
+        if id == StaffId(0) {
+            self.owner_name = name;
+        } else if let Some(member) = self.members.iter_mut().find(|m| m.id == id) {
+            member.name = name;
+        }
+    }
+}
+
+/// Trim a display name given by a member of staff, shortening it to [`MAX_NAME_LEN`]
+fn display_name(name: &str) -> String {
+    name.trim().chars().take(MAX_NAME_LEN).collect()
+}
+
+/// Renders the staff of a class, as shown in the teacher's view. The owner is given controls to invite and
+/// remove staff, and everyone can change their own name.
+pub fn panel(code: ClassCode, owner: &Staff, staff: &StaffList, viewer: &Staff) -> maud::Markup {
+    let manage = viewer.role.can(Permission::ManageClass);
+    let action = |a: &str| ui::url(format!("/class/{code}/staff/{a}"));
+
+    maud::html! {
+        ul {
+            @for member in std::iter::once(owner).chain(&staff.members) {
+                li {
+                    b { (member.name) } " (" (member.role) ")"
+                    @if member.id == viewer.id { " - you" }
+                    @if manage && member.role != Role::Owner {
+                        " "
+                        form class="inline-form" action=(action("remove")) method="post"
+                            onsubmit="return confirm('Remove this member of staff?')" {
+                            input type="hidden" name="id" value=(member.id.0) {}
+                            input type="submit" value="Remove" class="btn btn-error btn-ghost" {}
+                        }
+                    }
+                }
+            }
+        }
+
+        form class="t-form" action=(action("rename")) method="post" {
+            div class="form-group" {
+                label for="name" { "Your name: " }
+                input name="name" type="text" value=(viewer.name) maxlength=(MAX_NAME_LEN) required {}
+                input type="submit" value="Rename" class="btn btn-default btn-ghost" {}
+            }
+        }
+
+        @if manage {
+            @for (token, role) in staff.invites() {
+                @let link = ui::url(format!("/class/{code}/invite/{token}"));
+                p {
+                    "Invite for a " (role) ": " a href=(link) { (link) } " "
+                    form class="inline-form" action=(action("revoke")) method="post" {
+                        input type="hidden" name="invite" value=(token) {}
+                        input type="submit" value="Revoke" class="btn btn-error btn-ghost" {}
+                    }
+                }
+            }
+
+            form class="t-form" action=(action("invite")) method="post" {
+                div class="form-group" {
+                    label for="role" { "Invite a: " }
+                    select name="role" {
+                        option value="co_teacher" { "Co-teacher" }
+                        option value="assistant" { "Teaching assistant" }
+                    }
+                    input type="submit" value="Create invite link" class="btn btn-default btn-ghost" {}
+                }
+            }
+        }
+    }
+}
+
+/// Error type for requests to join or manage the staff of a class
+#[derive(thiserror::Error, Debug)]
+pub enum StaffError {
+    #[error(transparent)]
+    UnknownClass(#[from] UnknownClass),
+    /// Too many unknown class codes have been given by the client
+    #[error(transparent)]
+    RateLimited(LookupError),
+    #[error(transparent)]
+    Forbidden(#[from] Forbidden),
+    /// The invite doesn't exist, or has already been used
+    #[error("This invite link is invalid, or has already been used")]
+    InvalidInvite,
+    /// A class only ever has one owner
+    #[error("Can't invite another owner")]
+    InviteOwner,
+    /// Members of staff must have a name
+    #[error("Name must not be empty")]
+    EmptyName,
+}
+
+impl From<LookupError> for StaffError {
+    fn from(e: LookupError) -> StaffError {
+        match e {
+            LookupError::UnknownClass(e) => StaffError::UnknownClass(e),
+            e @ LookupError::RateLimited => StaffError::RateLimited(e),
+        }
+    }
+}
+
+impl IntoResponse for StaffError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            StaffError::Forbidden(e) => return e.into_response(),
+            StaffError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            StaffError::UnknownClass(_) | StaffError::InvalidInvite => StatusCode::NOT_FOUND,
+            StaffError::InviteOwner | StaffError::EmptyName => StatusCode::BAD_REQUEST,
+        };
+
+        let page = ui::base(
+            "Can't Change Staff",
+            maud::html! {
+                p { (self) "." }
+                a href=(ui::url("/")) { "Go back." };
+            },
+        );
+
+        (status, page).into_response()
+    }
+}
+
+/// Check that the request comes from a member of staff with a given permission
+fn require(
+    state: &AppState,
+    ip: IpAddr,
+    id: &str,
+    headers: &HeaderMap,
+    permission: Permission,
+) -> Result<(ClassCode, Staff), StaffError> {
+    let code = state.lookup_code(ip, id)?;
+
+    let member = auth::require_teacher(state, code, headers)?;
+    member.require(permission)?;
+
+    Ok((code, member))
+}
+
+/// Redirect back to the teacher's view of a class
+fn back(code: ClassCode) -> Redirect {
+    Redirect::to(&ui::url(format!("/class/{code}/teacher")))
+}
+
+/// Contains the data submitted when the owner creates an invite
+#[derive(Deserialize)]
+pub struct InviteData {
+    /// The role to invite someone as
+    role: Role,
+}
+
+/// Handler for the owner creating an invite link
+pub async fn invite(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Form(data): Form<InviteData>,
+) -> Result<Redirect, StaffError> {
+    let (code, _) = require(&state, ip, &id, &headers, Permission::ManageClass)?;
+
+    if data.role == Role::Owner {
+        return Err(StaffError::InviteOwner);
+    }
+
+    state.with_tickets_mut(code, |t| t.staff_mut().invite(data.role))?;
+
+    Ok(back(code))
+}
+
+/// Contains the data submitted when the owner revokes an invite
+#[derive(Deserialize)]
+pub struct RevokeData {
+    /// The token of the invite
+    invite: String,
+}
+
+/// Handler for the owner revoking an unused invite link
+pub async fn revoke(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Form(data): Form<RevokeData>,
+) -> Result<Redirect, StaffError> {
+    let (code, _) = require(&state, ip, &id, &headers, Permission::ManageClass)?;
+
+    state.with_tickets_mut(code, |t| t.staff_mut().revoke(&data.invite))?;
+
+    Ok(back(code))
+}
+
+/// Contains the data submitted when the owner removes a member of staff
+#[derive(Deserialize)]
+pub struct RemoveData {
+    /// The ID of the member to remove
+    id: StaffId,
+}
+
+/// Handler for the owner removing a member of staff from the class
+pub async fn remove(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Form(data): Form<RemoveData>,
+) -> Result<Redirect, StaffError> {
+    let (code, owner) = require(&state, ip, &id, &headers, Permission::ManageClass)?;
+
+    if state.with_tickets_mut(code, |t| t.staff_mut().remove(data.id))? {
+        tracing::info!(%code, owner = owner.name(), "removed member of staff");
+    }
+
+    Ok(back(code))
+}
+
+/// Contains the data submitted when a member of staff chooses their name
+#[derive(Deserialize)]
+pub struct NameData {
+    /// The display name
+    name: String,
+}
+
+/// Handler for a member of staff changing their own display name
+pub async fn rename(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Form(data): Form<NameData>,
+) -> Result<Redirect, StaffError> {
+    let (code, member) = require(&state, ip, &id, &headers, Permission::ManageTickets)?;
+
+    if data.name.trim().is_empty() {
+        return Err(StaffError::EmptyName);
+    }
+
+    state.with_tickets_mut(code, |t| t.staff_mut().rename(member.id(), &data.name))?;
+
+    Ok(back(code))
+}
+
+/// The form presented to someone that opens an invite link, asking for their name
+pub async fn invite_form(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path((id, token)): Path<(String, String)>,
+) -> Result<maud::Markup, StaffError> {
+    let code = state.lookup_code(ip, &id)?;
+    let role = state
+        .with_tickets(code, |t| t.staff().invite_role(&token))?
+        .ok_or(StaffError::InvalidInvite)?;
+
+    Ok(ui::base(
+        &format!("Join Class {code}"),
+        maud::html! {
+            p { "You have been invited to help run class " (code) " as a " (role) "." }
+
+            form class="t-form" method="post" {
+                fieldset {
+                    legend { "Details" }
+
+                    div class="form-group" {
+                        label for="name" { "Name: " }
+                        input name="name" type="text" maxlength=(MAX_NAME_LEN) required placeholder="Jane Doe" {}
+                    }
+
+                    div class="form-group" {
+                        input type="submit" value="Join" class="btn btn-default" {}
+                    }
+                }
+            }
+        },
+    ))
+}
+
+/// Handler for accepting an invite. The new member is given their token in a cookie, and sent to the
+/// teacher's view.
+pub async fn accept_invite(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path((id, token)): Path<(String, String)>,
+    Form(data): Form<NameData>,
+) -> Result<Response, StaffError> {
+    let code = state.lookup_code(ip, &id)?;
+
+    if data.name.trim().is_empty() {
+        return Err(StaffError::EmptyName);
+    }
+
+    let member = state.try_with_tickets_mut(code, |t| {
+        t.staff_mut()
+            .accept(&token, &data.name)
+            .ok_or(StaffError::InvalidInvite)
+    })?;
+
+    tracing::info!(%code, name = member.name(), role = %member.role(), "staff joined class");
+
+    Ok((auth::teacher_cookie(code, member.token()), back(code)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn role_permissions() {
+        use Permission::*;
+
+        let expected = [
+            (
+                Role::Owner,
+                [ManageTickets, DismissTickets, ExportTickets, ManageClass].as_slice(),
+            ),
+            (
+                Role::CoTeacher,
+                &[ManageTickets, DismissTickets, ExportTickets],
+            ),
+            (Role::Assistant, &[ManageTickets]),
+        ];
+
+        for (role, allowed) in expected {
+            for permission in [ManageTickets, DismissTickets, ExportTickets, ManageClass] {
+                assert_eq!(
+                    role.can(permission),
+                    allowed.contains(&permission),
+                    "{role} {permission}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn invites_are_used_once() {
+        let mut staff = StaffList::default();
+        let invite = staff.invite(Role::Assistant);
+        assert_eq!(
+            staff.invite_role(&invite.to_string()),
+            Some(Role::Assistant)
+        );
+
+        let member = staff.accept(&invite.to_string(), "  Sam  ").unwrap();
+        assert_eq!((member.name(), member.role()), ("Sam", Role::Assistant));
+        assert_ne!(member.id(), StaffId(0));
+        assert_eq!(
+            staff.find(&member.token().to_string()).map(Staff::id),
+            Some(member.id())
+        );
+
+        // the invite was spent
+        assert_eq!(staff.invite_role(&invite.to_string()), None);
+        assert!(staff.accept(&invite.to_string(), "Eve").is_none());
+        assert_eq!(staff.members().len(), 1);
+
+        // revoked invites can't be used either
+        let revoked = staff.invite(Role::CoTeacher);
+        assert!(staff.revoke(&revoked.to_string()));
+        assert!(!staff.revoke(&revoked.to_string()));
+        assert!(staff.accept(&revoked.to_string(), "Eve").is_none());
+    }
+
+    #[test]
+    fn members_are_renamed_and_removed() {
+        let mut staff = StaffList::default();
+        let first = staff.invite(Role::CoTeacher);
+        let first = staff.accept(&first.to_string(), "Sam").unwrap();
+        let second = staff.invite(Role::Assistant);
+        let second = staff.accept(&second.to_string(), "Alex").unwrap();
+        assert_ne!(first.id(), second.id());
+
+        staff.rename(first.id(), " Samantha ");
+        assert_eq!(
+            staff.find(&first.token().to_string()).unwrap().name(),
+            "Samantha"
+        );
+
+        let owner = Token::generate();
+        staff.rename(StaffId(0), "Ms Smith");
+        assert_eq!(staff.owner(&owner).name(), "Ms Smith");
+
+        // a removed member's token no longer works, and IDs aren't reused
+        assert!(staff.remove(first.id()));
+        assert!(!staff.remove(first.id()));
+        assert!(!staff.remove(StaffId(0)));
+        assert!(staff.find(&first.token().to_string()).is_none());
+
+        let third = staff.invite(Role::Assistant);
+        let third = staff.accept(&third.to_string(), "Jo").unwrap();
+        assert!(third.id() != first.id() && third.id() != second.id());
+    }
+
+    #[test]
+    fn names_are_truncated() {
+        let long = "x".repeat(MAX_NAME_LEN + 10);
+
+        let mut staff = StaffList::default();
+        let invite = staff.invite(Role::Assistant);
+        let member = staff.accept(&invite.to_string(), &long).unwrap();
+        assert_eq!(member.name(), "x".repeat(MAX_NAME_LEN));
+
+        staff.rename(StaffId(0), &long);
+        assert_eq!(
+            staff.owner(&Token::generate()).name(),
+            "x".repeat(MAX_NAME_LEN)
+        );
+    }
+}
@@ -3,37 +3,195 @@
 //! The [`AppState`] struct wraps over a map of [`ClassCode`]s to their [`TicketList`]s, and provides
 //! convenient methods for accessing that state.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::sync::{Arc, RwLock};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
 
-use base64ct::{Base64UrlUnpadded, Encoding};
 use serde::{Deserialize, Serialize};
 
-use web_push_native::jwt_simple::algorithms::{ES256KeyPair, ECDSAP256KeyPairLike};
+use tokio::sync::{broadcast, Notify};
 
-use crate::ticket::TicketList;
+use web_push_native::jwt_simple::algorithms::ES256KeyPair;
+
+use crate::events::ClassEvent;
+use crate::metrics::Metrics;
+use crate::push;
+use crate::queue::Policy;
+use crate::ratelimit::RateLimiter;
+use crate::storage::{Storage, StorageError};
+use crate::ticket::{Actor, Status, Ticket, TicketError, TicketId, TicketList};
+use crate::vapid;
 
 /// Error type when an invalid class code is given.
 #[derive(thiserror::Error, Debug)]
 #[error("Unknown class code {0}")]
-pub struct UnknownClass(ClassCode);
+pub struct UnknownClass(String);
+
+/// Error type when every class code is already in use.
+#[derive(thiserror::Error, Debug)]
+#[error("No class codes are available, please try again later")]
+pub struct ClassesFull;
 
-/// A class code. Wrapped up in a struct to ensure that any `ClassCode` we have access to is valid
-#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
-pub struct ClassCode(u16);
+/// Error type when looking up a class by its code fails
+#[derive(thiserror::Error, Debug)]
+pub enum LookupError {
+    #[error(transparent)]
+    UnknownClass(#[from] UnknownClass),
+    /// The user has entered too many unknown codes recently
+    #[error("Too many unknown class codes entered, please wait a few minutes and try again")]
+    RateLimited,
+}
+
+/// The maximum length of a [`ClassCode`]
+pub const MAX_CODE_LEN: usize = 16;
+
+/// A class code, made up of uppercase ASCII letters and digits. Wrapped up in a struct to ensure that any
+/// `ClassCode` we have access to is valid, and stored inline so that it can be cheaply copied
+#[derive(Copy, Clone, Hash, PartialEq, Eq)]
+pub struct ClassCode {
+    /// The characters of the code. Only the first `len` bytes are used
+    bytes: [u8; MAX_CODE_LEN],
+    /// The length of the code
+    len: u8,
+}
 
 impl ClassCode {
-    /// Return the inner `u16` contained in the class code
-    pub fn as_u16(&self) -> u16 {
-        self.0
+    /// Parse a class code entered by a user. Codes are case-insensitive, so are converted to uppercase.
+    /// Returns `None` if the string could never be a valid code.
+    pub fn parse(code: &str) -> Option<ClassCode> {
+        let code = code.trim();
+
+        let valid = !code.is_empty()
+            && code.len() <= MAX_CODE_LEN
+            && code.bytes().all(|b| b.is_ascii_alphanumeric());
+
+        if !valid {
+            return None;
+        }
+
+        let mut bytes = [0; MAX_CODE_LEN];
+        bytes[..code.len()].copy_from_slice(code.to_ascii_uppercase().as_bytes());
+
+        Some(ClassCode {
+            bytes,
+            len: code.len() as u8,
+        })
+    }
+
+    /// Return the class code as a string
+    pub fn as_str(&self) -> &str {
+        // codes are only ever constructed from ASCII, so this can never fail
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap()
     }
 }
 
 impl fmt::Display for ClassCode {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        // format inner u16 as 4-digit hex number
-        write!(fmt, "{:04X}", self.0)
+        write!(fmt, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for ClassCode {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "ClassCode({})", self.as_str())
+    }
+}
+
+impl Serialize for ClassCode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ClassCode {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = String::deserialize(deserializer)?;
+        ClassCode::parse(&code)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid class code {code:?}")))
+    }
+}
+
+/// Error type when an invalid [`CodeFormat`] is given
+#[derive(thiserror::Error, Debug)]
+pub enum CodeFormatError {
+    #[error("class codes must be between 1 and {MAX_CODE_LEN} characters long")]
+    Length,
+    #[error("class code alphabet must contain at least 2 distinct ASCII letters or digits")]
+    Alphabet,
+    #[error("class codes must be longer, or use more characters, so that there are at least 2^20 of them")]
+    Space,
+}
+
+/// Describes the codes generated for new classes
+#[derive(Debug, Clone)]
+pub struct CodeFormat {
+    /// The number of characters in a code
+    length: usize,
+    /// The characters a code is made up of
+    alphabet: Vec<u8>,
+}
+
+impl CodeFormat {
+    /// The default alphabet, which avoids characters that are easily confused (`0`/`O`, `1`/`I`/`L`)
+    pub const DEFAULT_ALPHABET: &'static str = "ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+
+    /// The fewest distinct codes a format may have, so that codes can't easily be guessed
+    pub const MIN_SPACE: u64 = 1 << 20;
+
+    /// Create a new code format. Letters in the alphabet are converted to uppercase, as codes are
+    /// case-insensitive. The format must have at least [`CodeFormat::MIN_SPACE`] codes.
+    pub fn new(length: usize, alphabet: &str) -> Result<CodeFormat, CodeFormatError> {
+        if length == 0 || length > MAX_CODE_LEN {
+            return Err(CodeFormatError::Length);
+        }
+
+        let mut alphabet: Vec<u8> = alphabet.to_ascii_uppercase().into_bytes();
+        alphabet.sort_unstable();
+        alphabet.dedup();
+
+        if alphabet.len() < 2 || !alphabet.iter().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(CodeFormatError::Alphabet);
+        }
+
+        let format = CodeFormat { length, alphabet };
+
+        if format.space() < CodeFormat::MIN_SPACE {
+            return Err(CodeFormatError::Space);
+        }
+
+        Ok(format)
+    }
+
+    /// The number of distinct codes in this format, saturating at `u64::MAX`
+    fn space(&self) -> u64 {
+        (self.alphabet.len() as u64).saturating_pow(self.length as u32)
+    }
+
+    /// Generate a random code of this format
+    fn random(&self) -> ClassCode {
+        use rand::seq::SliceRandom;
+
+        let mut rng = rand::thread_rng();
+        let code: String = (0..self.length)
+            .map(|_| *self.alphabet.choose(&mut rng).unwrap() as char)
+            .collect();
+
+        ClassCode::parse(&code).unwrap()
+    }
+
+    /// Generate the `n`th code of this format, for enumerating every code
+    fn nth(&self, mut n: u64) -> ClassCode {
+        let base = self.alphabet.len() as u64;
+        let code: String = (0..self.length)
+            .map(|_| {
+                let c = self.alphabet[(n % base) as usize] as char;
+                n /= base;
+                c
+            })
+            .collect();
+
+        ClassCode::parse(&code).unwrap()
     }
 }
 
@@ -46,6 +204,45 @@ pub struct AppState {
 
     /// VAPID signature, used for sending push notifications to client
     vapid: Arc<ES256KeyPair>,
+
+    /// Contact details sent to push services alongside the VAPID signature
+    vapid_subject: Arc<str>,
+
+    /// HTTP client, used for delivering push notifications to push services
+    http: reqwest::Client,
+
+    /// Backend that classes are persisted to whenever they are modified
+    storage: Arc<dyn Storage>,
+
+    /// Classes modified or closed since they were last written to the storage backend
+    dirty: Arc<Mutex<HashSet<ClassCode>>>,
+
+    /// Wakes the background task that writes modified classes to the storage backend
+    flush_needed: Arc<Notify>,
+
+    /// Held while writing to the storage backend, so that an older copy of a class can't overwrite a newer one
+    flushing: Arc<tokio::sync::Mutex<()>>,
+
+    /// Broadcast channels for each class that has clients listening for events
+    channels: Arc<Mutex<HashMap<ClassCode, broadcast::Sender<ClassEvent>>>>,
+
+    /// The format of codes generated for new classes
+    code_format: Arc<CodeFormat>,
+
+    /// Limits how many unknown class codes each user may enter, to prevent codes being enumerated
+    limiter: Arc<RateLimiter>,
+
+    /// Metrics about how busy the server is, reported to Prometheus
+    metrics: Arc<Metrics>,
+}
+
+/// A summary of a class's activity, for reporting metrics
+pub struct ClassActivity {
+    pub code: ClassCode,
+    /// The number of tickets that are still open
+    pub open: usize,
+    /// The number of clients connected to the class's event stream
+    pub listeners: usize,
 }
 
 struct ClassDebug(ClassCode, usize);
@@ -57,41 +254,73 @@ impl fmt::Debug for ClassDebug {
     }
 }
 
+/// Formats every class as a set of [`ClassDebug`]s
+struct ClassSet<'a>(&'a HashMap<ClassCode, TicketList>);
+
+impl fmt::Debug for ClassSet<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set()
+            .entries(
+                self.0
+                    .iter()
+                    .map(|(key, list)| ClassDebug(*key, list.len())),
+            )
+            .finish()
+    }
+}
+
 impl fmt::Debug for AppState {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let classes = self.classes.read().unwrap();
 
-        let vapid_pub = Base64UrlUnpadded::encode_string(
-            &self
-                .vapid()
-                .key_pair()
-                .public_key()
-                .to_bytes_uncompressed(),
-        );
-
-        let mut class_set = f.debug_set();
-
-        for (key, list) in classes.iter() {
-            class_set.entry(&ClassDebug(*key, list.len()));
-        }
-
-        let class_set = class_set.finish()?;
+        let vapid_pub = vapid::public_key(self.vapid());
 
         f.debug_struct("AppState")
             .field("vapid_pub", &vapid_pub)
-            .field("classes", &class_set)
+            .field("classes", &ClassSet(&classes))
             .finish()
     }
 }
 
 impl AppState {
-    /// Create an empty instance of the application state
-    pub fn init() -> AppState {
-        AppState {
-            classes: Arc::new(RwLock::new(HashMap::new())),
-            // generate a new VAPID keypair for the server
-            vapid: Arc::new(ES256KeyPair::generate()),
-        }
+    /// Create an instance of the application state, loading any classes saved in the storage backend. Modified
+    /// classes are written back by a background task, so this must be called from within a Tokio runtime.
+    pub fn init(
+        storage: Box<dyn Storage>,
+        vapid: ES256KeyPair,
+        vapid_subject: String,
+        code_format: CodeFormat,
+        limiter: RateLimiter,
+        metrics: Metrics,
+    ) -> Result<AppState, StorageError> {
+        let classes: HashMap<_, _> = storage.load()?.into_iter().collect();
+        tracing::info!(count = classes.len(), "loaded classes from storage");
+
+        let state = AppState {
+            classes: Arc::new(RwLock::new(classes)),
+            vapid: Arc::new(vapid),
+            vapid_subject: Arc::from(vapid_subject),
+            http: push::client(),
+            storage: Arc::from(storage),
+            dirty: Arc::new(Mutex::new(HashSet::new())),
+            flush_needed: Arc::new(Notify::new()),
+            flushing: Arc::new(tokio::sync::Mutex::new(())),
+            channels: Arc::new(Mutex::new(HashMap::new())),
+            code_format: Arc::new(code_format),
+            limiter: Arc::new(limiter),
+            metrics: Arc::new(metrics),
+        };
+
+        // write modified classes in the background, so requests never wait for the storage backend
+        let writer = state.clone();
+        tokio::spawn(async move {
+            loop {
+                writer.flush_needed.notified().await;
+                writer.flush().await;
+            }
+        });
+
+        Ok(state)
     }
 
     /// Returns a reference to the VAPID key
@@ -99,67 +328,606 @@ impl AppState {
         &self.vapid
     }
 
-    /// Randomly generates class codes, returns the first one that is not yet in use
-    fn get_unique_code(&self) -> ClassCode {
-        // aquire a read lock on the class list
-        let classes = self.classes.read().unwrap();
+    /// Returns the contact details sent to push services alongside the VAPID signature
+    pub fn vapid_subject(&self) -> &str {
+        &self.vapid_subject
+    }
+
+    /// Returns the server's metrics
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Returns the HTTP client used for sending push notifications
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
 
-        loop {
+    /// Randomly generates class codes, returns the first one that is not yet in use. Produces an error if
+    /// every code is in use.
+    fn get_unique_code(
+        &self,
+        classes: &HashMap<ClassCode, TicketList>,
+    ) -> Result<ClassCode, ClassesFull> {
+        /// The number of random codes to try before giving up
+        const ATTEMPTS: usize = 64;
+        /// The largest number of codes we're willing to search through one-by-one
+        const SEARCH_LIMIT: u64 = 1 << 20;
+
+        let format = &self.code_format;
+        let space = format.space();
+
+        if classes.len() as u64 >= space {
+            // every code is in use
+            return Err(ClassesFull);
+        }
+
+        for _ in 0..ATTEMPTS {
             // generate random code
-            let code = ClassCode(rand::random());
+            let code = format.random();
 
             if !classes.contains_key(&code) {
-                // code isn't in use, break & return
-                break code;
+                // code isn't in use, return
+                return Ok(code);
             }
         }
+
+        if space > SEARCH_LIMIT {
+            // the code space is too large to search, and must be almost entirely in use
+            return Err(ClassesFull);
+        }
+
+        // most codes are in use, so search every code (starting from a random one) for a free one
+        let start = rand::random::<u64>() % space;
+
+        (0..space)
+            .map(|offset| format.nth((start + offset) % space))
+            .find(|code| !classes.contains_key(code))
+            .ok_or(ClassesFull)
     }
 
     /// Creates a new class and returns it's unique code
-    pub fn create_class(&self) -> ClassCode {
-        // get a unique code & acquire write lock on classes
-        let code = self.get_unique_code();
+    pub fn create_class(&self) -> Result<ClassCode, ClassesFull> {
+        // acquire write lock on classes & get a unique code; the lock is held throughout, so that another
+        // class can't take the code before we insert it
         let mut classes = self.classes.write().unwrap();
+        let code = self.get_unique_code(&classes)?;
 
-        // insert empty `TicketList` into class
+        // insert empty `TicketList` into class, and persist it
         classes.insert(code, TicketList::new());
+        self.persist(code);
 
         // return class code
-        code
+        Ok(code)
+    }
+
+    /// Close a class, removing it and all of its tickets. Anybody listening to the class is notified.
+    pub fn close_class(&self, code: ClassCode) -> Result<(), UnknownClass> {
+        let mut classes = self.classes.write().unwrap();
+
+        if classes.remove(&code).is_none() {
+            return Err(UnknownClass(code.to_string()));
+        }
+
+        // the writer removes classes that are no longer in memory
+        self.persist(code);
+        self.metrics.remove_class(code);
+
+        // notify listeners, then drop the channel so their streams end
+        self.publish(code, ClassEvent::ClassClosed);
+        self.channels.lock().unwrap().remove(&code);
+
+        Ok(())
+    }
+
+    /// Close every class that has had no activity within the `timeout`, and has nobody listening to it.
+    /// Returns the number of classes closed.
+    pub fn expire_classes(&self, timeout: chrono::Duration) -> usize {
+        let cutoff = chrono::Utc::now() - timeout;
+
+        // find idle classes, without holding the lock while closing them
+        let idle: Vec<_> = {
+            let classes = self.classes.read().unwrap();
+            let channels = self.channels.lock().unwrap();
+
+            classes
+                .iter()
+                .filter(|(_, t)| t.last_active() < cutoff)
+                .filter(|(code, _)| {
+                    // a teacher or student watching the class counts as activity
+                    channels
+                        .get(code)
+                        .map(|c| c.receiver_count() == 0)
+                        .unwrap_or(true)
+                })
+                .map(|(code, _)| *code)
+                .collect()
+        };
+
+        for code in &idle {
+            tracing::info!(%code, "closing idle class");
+            // the class may have been closed since we checked, which is fine
+            let _ = self.close_class(*code);
+        }
+
+        idle.len()
     }
 
-    /// Retrieve a [`ClassCode`] from a `u16`. If the given code is not in use, then produce an error.
-    pub fn get_code(&self, id: u16) -> Result<ClassCode, UnknownClass> {
-        // acquire read lock on classes & wrap ID in `ClassCode` for comparison
+    /// Retrieve a [`ClassCode`] from a string. If the given code is not in use, then produce an error.
+    /// Handlers should use [`AppState::lookup_code`] instead, so that codes can't be guessed.
+    fn get_code(&self, id: &str) -> Result<ClassCode, UnknownClass> {
+        // acquire read lock on classes & parse code for comparison
         let classes = self.classes.read().unwrap();
-        let code = ClassCode(id);
 
-        if classes.contains_key(&code) {
+        match ClassCode::parse(id) {
             // class exists; return code
-            Ok(code)
-        } else {
+            Some(code) if classes.contains_key(&code) => Ok(code),
             // class doesn't exist; return error
-            Err(UnknownClass(code))
+            _ => Err(UnknownClass(id.to_string())),
         }
     }
 
-    /// Perform an immutable operation on a given class's [`TicketList`]
-    pub fn with_tickets<T>(&self, code: ClassCode, op: impl Fn(&TicketList) -> T) -> T {
+    /// Retrieve a [`ClassCode`] entered by a user, as with [`AppState::get_code`]. Failed lookups are
+    /// counted against the user's IP address, and once there are too many, all lookups are refused.
+    pub fn lookup_code(&self, ip: IpAddr, id: &str) -> Result<ClassCode, LookupError> {
+        if self.limiter.is_limited(ip) {
+            return Err(LookupError::RateLimited);
+        }
+
+        self.get_code(id).map_err(|e| {
+            self.limiter.record_failure(ip);
+            e.into()
+        })
+    }
+
+    /// Find the address of the client that sent a request, see [`RateLimiter::client_ip`]
+    pub fn client_ip(&self, peer: IpAddr, headers: &axum::http::HeaderMap) -> IpAddr {
+        self.limiter.client_ip(peer, headers)
+    }
+
+    /// Perform an immutable operation on a given class's [`TicketList`]. Produces an error if the class has
+    /// been closed since its code was looked up.
+    pub fn with_tickets<T>(
+        &self,
+        code: ClassCode,
+        op: impl Fn(&TicketList) -> T,
+    ) -> Result<T, UnknownClass> {
         // acquire read lock & retrieve reference to ticket list
         let classes = self.classes.read().unwrap();
-        let tickets = classes.get(&code).unwrap();
+        let tickets = classes
+            .get(&code)
+            .ok_or_else(|| UnknownClass(code.to_string()))?;
 
         // perform operation on ticket list
-        op(tickets)
+        Ok(op(tickets))
     }
 
-    /// Perform mutable operation on a given class's [`TicketList`]
-    pub fn with_tickets_mut<T>(&self, code: ClassCode, op: impl Fn(&mut TicketList) -> T) -> T {
+    /// Perform mutable operation on a given class's [`TicketList`]. Produces an error if the class has been
+    /// closed since its code was looked up.
+    pub fn with_tickets_mut<T>(
+        &self,
+        code: ClassCode,
+        op: impl FnOnce(&mut TicketList) -> T,
+    ) -> Result<T, UnknownClass> {
+        self.try_with_tickets_mut(code, |t| Ok::<_, UnknownClass>(op(t)))
+    }
+
+    /// Perform a mutable operation that may fail on a given class's [`TicketList`]. The class only counts as
+    /// active, and is only persisted, if the operation succeeds.
+    pub fn try_with_tickets_mut<T, E: From<UnknownClass>>(
+        &self,
+        code: ClassCode,
+        op: impl FnOnce(&mut TicketList) -> Result<T, E>,
+    ) -> Result<T, E> {
         // acquire read lock & retrieve mutable reference to ticket list
         let mut classes = self.classes.write().unwrap();
-        let tickets = classes.get_mut(&code).unwrap();
+        let tickets = classes
+            .get_mut(&code)
+            .ok_or_else(|| UnknownClass(code.to_string()))?;
 
-        // perform operation on ticket list
-        op(tickets)
+        // perform operation on ticket list, and persist the result
+        let res = op(tickets)?;
+        tickets.touch();
+        self.persist(code);
+
+        Ok(res)
+    }
+
+    /// Perform a mutable operation on a class that may have since been closed, such as when a background
+    /// task finishes. Unlike [`AppState::with_tickets_mut`], this doesn't count as activity in the class.
+    /// Returns `None` if the class no longer exists.
+    pub fn update_class<T>(
+        &self,
+        code: ClassCode,
+        op: impl FnOnce(&mut TicketList) -> T,
+    ) -> Option<T> {
+        let mut classes = self.classes.write().unwrap();
+        let tickets = classes.get_mut(&code)?;
+
+        let res = op(tickets);
+        self.persist(code);
+
+        Some(res)
+    }
+
+    /// Move a ticket to a new status, and notify anybody listening to the class. Returns the updated ticket.
+    pub fn transition(
+        &self,
+        code: ClassCode,
+        id: TicketId,
+        status: Status,
+        actor: Actor,
+        summary: Option<&str>,
+    ) -> Result<Ticket, TicketError> {
+        let ticket = self.try_with_tickets_mut(code, |t| {
+            t.transition(id, status, actor.clone(), summary)?;
+            Ok::<_, TicketError>(t.get(id).cloned().unwrap())
+        })?;
+
+        if status == Status::Resolved {
+            self.metrics.record_resolved(code);
+        }
+
+        if ticket.status().is_open() {
+            // tickets that are still open are re-rendered
+            self.publish(code, ClassEvent::TicketUpdated(ticket.clone()));
+        } else {
+            // closed tickets are removed from the view
+            self.publish(code, ClassEvent::TicketDismissed(id));
+        }
+
+        Ok(ticket)
+    }
+
+    /// Bump a ticket up the queue (or undo a bump), and notify anybody listening to the class. Returns the
+    /// updated ticket.
+    pub fn bump(&self, code: ClassCode, id: TicketId, bumped: bool) -> Result<Ticket, TicketError> {
+        let ticket = self.try_with_tickets_mut(code, |t| {
+            t.bump(id, bumped)?;
+            Ok::<_, TicketError>(t.get(id).cloned().unwrap())
+        })?;
+
+        self.publish(code, ClassEvent::TicketUpdated(ticket.clone()));
+
+        Ok(ticket)
+    }
+
+    /// Add a private note to a ticket on behalf of a member of staff, and notify anybody listening to the
+    /// class. Returns the updated ticket.
+    pub fn add_note(
+        &self,
+        code: ClassCode,
+        id: TicketId,
+        actor: Actor,
+        text: &str,
+    ) -> Result<Ticket, TicketError> {
+        let ticket = self.try_with_tickets_mut(code, |t| {
+            t.add_note(id, actor.clone(), text)?;
+            Ok::<_, TicketError>(t.get(id).cloned().unwrap())
+        })?;
+
+        // closed tickets aren't in the teacher's view, so there's nothing to update
+        if ticket.status().is_open() {
+            self.publish(code, ClassEvent::TicketUpdated(ticket.clone()));
+        }
+
+        Ok(ticket)
+    }
+
+    /// Post a message to a ticket's thread, and deliver it to anybody listening to the class. Returns the
+    /// updated ticket.
+    pub fn post_message(
+        &self,
+        code: ClassCode,
+        id: TicketId,
+        author: Actor,
+        text: &str,
+    ) -> Result<Ticket, TicketError> {
+        let ticket = self.try_with_tickets_mut(code, |t| {
+            t.post_message(id, author.clone(), text)?;
+            Ok::<_, TicketError>(t.get(id).cloned().unwrap())
+        })?;
+
+        self.publish(code, ClassEvent::MessagePosted(ticket.clone()));
+
+        Ok(ticket)
+    }
+
+    /// Mark a ticket's thread as read by the class's staff, clearing the unread count shown on every staff
+    /// member's view
+    pub fn mark_read(&self, code: ClassCode, id: TicketId) -> Result<(), TicketError> {
+        // viewing a thread that has already been read changes nothing, so needn't lock or persist the class
+        let unread = self.with_tickets(code, |t| t.get(id).map(|t| t.unread()))?;
+        if unread == Some(0) {
+            return Ok(());
+        }
+
+        let ticket = self.try_with_tickets_mut(code, |t| {
+            let unread = t.mark_read(id)?;
+            Ok::<_, TicketError>(t.get(id).filter(|_| unread).cloned())
+        })?;
+
+        // only open tickets are in the teacher's view
+        if let Some(ticket) = ticket.filter(|t| t.status().is_open()) {
+            self.publish(code, ClassEvent::TicketUpdated(ticket));
+        }
+
+        Ok(())
+    }
+
+    /// Summarise the activity of every class
+    pub fn activity(&self) -> Vec<ClassActivity> {
+        let classes = self.classes.read().unwrap();
+        let channels = self.channels.lock().unwrap();
+
+        classes
+            .iter()
+            .map(|(&code, list)| ClassActivity {
+                code,
+                open: list.tickets().filter(|t| t.status().is_open()).count(),
+                listeners: channels.get(&code).map(|c| c.receiver_count()).unwrap_or(0),
+            })
+            .collect()
+    }
+
+    /// Subscribe to the events of a given class
+    pub fn listen(&self, code: ClassCode) -> broadcast::Receiver<ClassEvent> {
+        let mut channels = self.channels.lock().unwrap();
+
+        channels
+            .entry(code)
+            // create the class's channel if nobody has listened to it yet
+            .or_insert_with(|| broadcast::channel(64).0)
+            .subscribe()
+    }
+
+    /// Send an event to everybody listening to a given class. If the class's queue isn't in the order tickets
+    /// were opened, a change to a ticket may move other tickets too, so listeners are also told to re-fetch
+    /// the list.
+    pub fn publish(&self, code: ClassCode, event: ClassEvent) {
+        let reorder = match event {
+            ClassEvent::TicketAdded(_)
+            | ClassEvent::TicketUpdated(_)
+            | ClassEvent::TicketDismissed(_) => {
+                // the class may have been closed in the meantime
+                let classes = self.classes.read().unwrap();
+                classes
+                    .get(&code)
+                    .map(|t| t.policy() != Policy::Fifo)
+                    .unwrap_or(false)
+            }
+            _ => false,
+        };
+
+        let mut channels = self.channels.lock().unwrap();
+
+        if let Some(sender) = channels.get(&code) {
+            // sending only fails if there are no receivers left, in which case we can drop the channel
+            let sent = sender.send(event).is_ok()
+                && (!reorder || sender.send(ClassEvent::Reordered).is_ok());
+
+            if !sent {
+                channels.remove(&code);
+            }
+        }
+    }
+
+    /// Schedule a class to be written to the storage backend (or removed from it, if it has been closed) by
+    /// the background writer. Several changes made before the writer gets to the class are written at once.
+    fn persist(&self, code: ClassCode) {
+        self.dirty.lock().unwrap().insert(code);
+        self.flush_needed.notify_one();
+    }
+
+    /// Write every class modified since the last flush to the storage backend, and remove every class closed
+    /// since then. Each class is copied so that it isn't locked while being written, and the blocking write
+    /// is done off the async executor. Failures are logged, as the in-memory state is still valid.
+    pub async fn flush(&self) {
+        let _flushing = self.flushing.lock().await;
+        let dirty: Vec<_> = self.dirty.lock().unwrap().drain().collect();
+
+        for code in dirty {
+            let tickets = self.classes.read().unwrap().get(&code).cloned();
+            let storage = self.storage.clone();
+
+            let res = tokio::task::spawn_blocking(move || match tickets {
+                Some(tickets) => storage.save(code, &tickets),
+                None => storage.remove(code),
+            })
+            .await;
+
+            match res {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => tracing::error!(%code, error = %e, "failed to persist class"),
+                Err(e) => tracing::error!(%code, error = %e, "storage task failed"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Storage backend recording each write made to it
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl Storage for Arc<Recorder> {
+        fn load(&self) -> Result<Vec<(ClassCode, TicketList)>, StorageError> {
+            Ok(vec![])
+        }
+
+        fn save(&self, code: ClassCode, _tickets: &TicketList) -> Result<(), StorageError> {
+            self.0.lock().unwrap().push(format!("save {code}"));
+            Ok(())
+        }
+
+        fn remove(&self, code: ClassCode) -> Result<(), StorageError> {
+            self.0.lock().unwrap().push(format!("remove {code}"));
+            Ok(())
+        }
+    }
+
+    impl Recorder {
+        /// Returns the writes made since this was last called
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut self.0.lock().unwrap())
+        }
+    }
+
+    fn state(storage: Arc<Recorder>) -> AppState {
+        AppState::init(
+            Box::new(storage),
+            ES256KeyPair::generate(),
+            "mailto:test@example.com".to_string(),
+            CodeFormat::new(6, CodeFormat::DEFAULT_ALPHABET).unwrap(),
+            RateLimiter::new(10, std::time::Duration::from_secs(60), vec![]),
+            Metrics::new(None, false),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn code_format_validation() {
+        assert!(matches!(
+            CodeFormat::new(0, "AB"),
+            Err(CodeFormatError::Length)
+        ));
+        assert!(matches!(
+            CodeFormat::new(MAX_CODE_LEN + 1, "AB"),
+            Err(CodeFormatError::Length)
+        ));
+        // duplicates (ignoring case) don't count towards the 2 characters needed
+        assert!(matches!(
+            CodeFormat::new(4, "aA"),
+            Err(CodeFormatError::Alphabet)
+        ));
+        assert!(matches!(
+            CodeFormat::new(4, "AB-"),
+            Err(CodeFormatError::Alphabet)
+        ));
+        // formats with too few codes to be unguessable
+        assert!(matches!(
+            CodeFormat::new(2, "AB"),
+            Err(CodeFormatError::Space)
+        ));
+        assert!(matches!(
+            CodeFormat::new(19, "AB"),
+            Err(CodeFormatError::Length)
+        ));
+        assert!(matches!(
+            CodeFormat::new(9, "ABCd"),
+            Err(CodeFormatError::Space)
+        ));
+        // exactly 2^20 codes
+        assert!(CodeFormat::new(10, "ABCd").is_ok());
+        assert!(CodeFormat::new(6, CodeFormat::DEFAULT_ALPHABET).is_ok());
+    }
+
+    #[test]
+    fn code_format_generates_parseable_codes() {
+        let format = CodeFormat::new(13, "ab2").unwrap();
+        assert_eq!(format.space(), 3u64.pow(13));
+
+        let codes = (0..10)
+            .map(|_| format.random())
+            .chain((0..1000).map(|n| format.nth(n)));
+        for code in codes.chain([format.nth(format.space() - 1)]) {
+            assert_eq!(code.as_str().len(), 13);
+            assert!(code.as_str().bytes().all(|b| b"AB2".contains(&b)));
+            // codes are entered case-insensitively
+            assert!(ClassCode::parse(&code.as_str().to_lowercase()) == Some(code));
+        }
+
+        // codes are enumerated without repeats
+        let codes: HashSet<_> = (0..1000).map(|n| format.nth(n)).collect();
+        assert_eq!(codes.len(), 1000);
+
+        // the space saturates rather than overflowing
+        assert_eq!(
+            CodeFormat::new(MAX_CODE_LEN, CodeFormat::DEFAULT_ALPHABET)
+                .unwrap()
+                .space(),
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn class_code_parsing() {
+        assert_eq!(ClassCode::parse(" abc1 ").unwrap().as_str(), "ABC1");
+        assert!(ClassCode::parse("").is_none());
+        assert!(ClassCode::parse("AB-C").is_none());
+        assert!(ClassCode::parse(&"A".repeat(MAX_CODE_LEN + 1)).is_none());
+    }
+
+    #[tokio::test]
+    async fn writes_changes_once_flushed() {
+        let storage = Arc::new(Recorder::default());
+        let state = state(storage.clone());
+
+        let code = state.create_class().unwrap();
+        // several changes before a flush are written together
+        for name in ["Alice", "Bob"] {
+            state
+                .with_tickets_mut(code, |t| {
+                    t.add_ticket(name, None::<&str>, None::<&str>, None)
+                        .unwrap()
+                })
+                .unwrap();
+        }
+        state.flush().await;
+        assert_eq!(storage.take(), [format!("save {code}")]);
+
+        state.close_class(code).unwrap();
+        state.flush().await;
+        assert_eq!(storage.take(), [format!("remove {code}")]);
+    }
+
+    #[tokio::test]
+    async fn reading_a_read_thread_writes_nothing() {
+        let storage = Arc::new(Recorder::default());
+        let state = state(storage.clone());
+
+        let code = state.create_class().unwrap();
+        let id = state
+            .with_tickets_mut(code, |t| {
+                t.add_ticket("Alice", None::<&str>, None::<&str>, None)
+                    .unwrap()
+            })
+            .unwrap()
+            .id();
+        state.flush().await;
+        storage.take();
+
+        state.mark_read(code, id).unwrap();
+        state.flush().await;
+        assert!(storage.take().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_changes_write_nothing() {
+        let storage = Arc::new(Recorder::default());
+        let state = state(storage.clone());
+
+        let code = state.create_class().unwrap();
+        let open = || {
+            state.try_with_tickets_mut(code, |t| {
+                t.add_ticket("Alice", None::<&str>, None::<&str>, None)
+            })
+        };
+        open().unwrap();
+        state.flush().await;
+        storage.take();
+        let active = state.with_tickets(code, |t| t.last_active()).unwrap();
+
+        // the duplicate ticket is refused, so the class is neither written nor counted as active
+        assert!(matches!(open(), Err(TicketError::Duplicate(_))));
+        state.flush().await;
+        assert!(storage.take().is_empty());
+        assert_eq!(
+            state.with_tickets(code, |t| t.last_active()).unwrap(),
+            active
+        );
     }
 }
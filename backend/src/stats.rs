@@ -0,0 +1,376 @@
+//! This module defines the statistics page for a class, showing how busy the class has been and how quickly
+//! students are being helped.
+//!
+//! This module is used to define the endpoint *GET* `/class/{id}/stats` ([`view`]), which is only accessible
+//! to the class's staff.
+//!
+//! Every statistic is computed from the tickets' timestamps and status changes (see [`Stats::new`]), and
+//! charts are rendered as inline SVG on the server, so the page works without JavaScript. Charts are styled
+//! by `style.css`, so that they match the rest of the UI.
+
+use std::collections::HashMap;
+
+use axum::extract::{Path, State};
+use axum::http::HeaderMap;
+
+use chrono::{DateTime, Duration, DurationRound, Local, Utc};
+
+use crate::auth;
+use crate::class::ClassError;
+use crate::ratelimit::ClientIp;
+use crate::staff::Permission;
+use crate::state::AppState;
+use crate::ticket::{normalise_name, Status, TicketList};
+use crate::ui;
+
+/// The most hours shown in the chart of tickets per hour; older hours are left out
+const MAX_HOURS: usize = 48;
+
+/// The number of students and categories shown in their charts
+const TOP_N: usize = 10;
+
+/// The median and 90th percentile of a set of durations
+pub struct Percentiles {
+    pub median: Duration,
+    pub p90: Duration,
+    /// The number of durations the percentiles were taken from
+    pub count: usize,
+}
+
+impl Percentiles {
+    /// Compute the percentiles of a set of durations, using the nearest-rank method. Returns `None` if there
+    /// are no durations.
+    fn new(mut durations: Vec<Duration>) -> Option<Percentiles> {
+        if durations.is_empty() {
+            return None;
+        }
+
+        durations.sort();
+        let rank = |p: usize| durations[(durations.len() * p).div_ceil(100).max(1) - 1];
+
+        Some(Percentiles {
+            median: rank(50),
+            p90: rank(90),
+            count: durations.len(),
+        })
+    }
+}
+
+/// Statistics about a class's tickets
+pub struct Stats {
+    /// The total number of tickets opened
+    pub total: usize,
+    /// The number of tickets opened in each hour (local time), oldest first, including hours with none
+    pub per_hour: Vec<(DateTime<Local>, usize)>,
+    /// How long tickets waited before a member of staff claimed or started helping with them
+    pub to_claim: Option<Percentiles>,
+    /// How long resolved tickets took to resolve, from when they were opened
+    pub to_resolve: Option<Percentiles>,
+    /// The students that opened the most tickets, busiest first
+    pub students: Vec<(String, usize)>,
+    /// The categories with the most tickets, most common first
+    pub categories: Vec<(String, usize)>,
+    /// The number of open tickets after each change to the queue, oldest first
+    pub queue: Vec<(DateTime<Utc>, usize)>,
+}
+
+impl Stats {
+    /// Compute the statistics for a class
+    pub fn new(list: &TicketList) -> Stats {
+        let tickets: Vec<_> = list.tickets().collect();
+
+        // tickets per hour, from the first ticket's hour to the last's
+        let hour = |at: DateTime<Utc>| {
+            let local = at.with_timezone(&Local);
+            local.duration_trunc(Duration::hours(1)).unwrap_or(local)
+        };
+        let mut counts: HashMap<DateTime<Local>, usize> = HashMap::new();
+        for ticket in &tickets {
+            *counts.entry(hour(ticket.created())).or_default() += 1;
+        }
+
+        let mut per_hour = vec![];
+        if let (Some(first), Some(last)) = (counts.keys().min(), counts.keys().max()) {
+            let mut at = (*last - Duration::hours(MAX_HOURS as i64 - 1)).max(*first);
+            while at <= *last {
+                per_hour.push((at, counts.get(&at).copied().unwrap_or(0)));
+                at += Duration::hours(1);
+            }
+        }
+
+        let to_claim = tickets
+            .iter()
+            .filter_map(|t| {
+                let claimed = t
+                    .history()
+                    .iter()
+                    .find(|tr| matches!(tr.status, Status::Claimed | Status::InProgress))?;
+                Some(claimed.timestamp - t.created())
+            })
+            .collect();
+
+        let to_resolve = tickets
+            .iter()
+            .filter_map(|t| Some(t.time_of(Status::Resolved)? - t.created()))
+            .collect();
+
+        // students are identified by name, but shown with the name they first used
+        let mut students: HashMap<String, (String, usize)> = HashMap::new();
+        for ticket in &tickets {
+            let entry = students
+                .entry(normalise_name(ticket.student()))
+                .or_insert_with(|| (ticket.student().to_string(), 0));
+            entry.1 += 1;
+        }
+
+        let mut categories: HashMap<String, usize> = HashMap::new();
+        for category in tickets.iter().filter_map(|t| t.category()) {
+            *categories.entry(category.to_string()).or_default() += 1;
+        }
+
+        // every change to the length of the queue: +1 when a ticket is opened, -1 when it is closed
+        let mut changes: Vec<(DateTime<Utc>, isize)> = vec![];
+        for ticket in &tickets {
+            changes.push((ticket.created(), 1));
+
+            if let Some(closed) = ticket.last_transition().filter(|t| !t.status.is_open()) {
+                changes.push((closed.timestamp, -1));
+            }
+        }
+        changes.sort_by_key(|(at, _)| *at);
+
+        let mut open = 0;
+        let mut queue: Vec<_> = changes
+            .into_iter()
+            .map(|(at, change)| {
+                open += change;
+                (at, open.max(0) as usize)
+            })
+            .collect();
+
+        // hold the current length until now, so the chart ends at the present
+        if let Some(&(_, current)) = queue.last() {
+            queue.push((Utc::now(), current));
+        }
+
+        Stats {
+            total: tickets.len(),
+            per_hour,
+            to_claim: Percentiles::new(to_claim),
+            to_resolve: Percentiles::new(to_resolve),
+            students: top(students.into_values()),
+            categories: top(categories),
+            queue,
+        }
+    }
+}
+
+/// Sort `(name, count)` pairs by count (and then name), keeping only the first [`TOP_N`]
+fn top(counts: impl IntoIterator<Item = (String, usize)>) -> Vec<(String, usize)> {
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|(a, x), (b, y)| y.cmp(x).then_with(|| a.cmp(b)));
+    counts.truncate(TOP_N);
+
+    counts
+}
+
+/// Format a duration as minutes and seconds
+fn print_duration(duration: Duration) -> String {
+    let secs = duration.num_seconds().max(0);
+
+    match secs / 60 {
+        0 => format!("{secs}s"),
+        mins => format!("{mins}m {:02}s", secs % 60),
+    }
+}
+
+/// Format a coordinate in a chart, rounded to keep the SVG small
+fn num(n: f64) -> String {
+    format!("{n:.1}")
+}
+
+/// Width of every chart, in SVG units
+const WIDTH: f64 = 600.0;
+
+/// Renders a vertical bar chart, labelling at most 12 of the bars so that the labels don't overlap
+fn bar_chart(bars: &[(String, usize)]) -> maud::Markup {
+    const HEIGHT: f64 = 160.0;
+    const LABELS: usize = 12;
+
+    let max = bars.iter().map(|(_, n)| *n).max().unwrap_or(0).max(1) as f64;
+    let step = WIDTH / bars.len().max(1) as f64;
+    let every = bars.len().div_ceil(LABELS).max(1);
+
+    maud::html! {
+        svg class="chart" viewBox=(format!("0 0 {WIDTH} {}", HEIGHT + 20.0)) role="img" {
+            @for (i, (label, n)) in bars.iter().enumerate() {
+                @let height = HEIGHT * *n as f64 / max;
+                @let x = i as f64 * step;
+
+                rect class="chart-bar" x=(num(x + 1.0)) y=(num(HEIGHT - height)) width=(num((step - 2.0).max(1.0))) height=(num(height)) {
+                    title { (label) ": " (n) }
+                }
+                @if i % every == 0 {
+                    text class="chart-text" x=(num(x + step / 2.0)) y=(num(HEIGHT + 15.0)) text-anchor="middle" { (label) }
+                }
+            }
+            line class="chart-axis" x1="0" y1=(HEIGHT) x2=(WIDTH) y2=(HEIGHT) {}
+            text class="chart-text" x="2" y="12" { "max " (max) }
+        }
+    }
+}
+
+/// Renders a horizontal bar chart, with each bar's label and value alongside it
+fn hbar_chart(bars: &[(String, usize)]) -> maud::Markup {
+    const ROW: f64 = 22.0;
+    const LABEL: f64 = 180.0;
+
+    let max = bars.iter().map(|(_, n)| *n).max().unwrap_or(0).max(1) as f64;
+    let height = ROW * bars.len() as f64;
+
+    maud::html! {
+        svg class="chart" viewBox=(format!("0 0 {WIDTH} {height}")) role="img" {
+            @for (i, (label, n)) in bars.iter().enumerate() {
+                @let y = i as f64 * ROW;
+                @let width = (WIDTH - LABEL - 40.0) * *n as f64 / max;
+
+                text class="chart-text" x=(num(LABEL - 6.0)) y=(num(y + 15.0)) text-anchor="end" { (label) }
+                rect class="chart-bar" x=(LABEL) y=(num(y + 3.0)) width=(num(width)) height=(num(ROW - 6.0)) {}
+                text class="chart-text" x=(num(LABEL + width + 6.0)) y=(num(y + 15.0)) { (n) }
+            }
+        }
+    }
+}
+
+/// Renders a step chart of a value over time, such as the length of the queue
+fn step_chart(points: &[(DateTime<Utc>, usize)]) -> maud::Markup {
+    const HEIGHT: f64 = 160.0;
+
+    let (Some((start, _)), Some((end, _))) = (points.first(), points.last()) else {
+        return maud::html! {};
+    };
+
+    let max = points.iter().map(|(_, n)| *n).max().unwrap_or(0).max(1) as f64;
+    let span = (*end - *start).num_seconds().max(1) as f64;
+    let x = |at: &DateTime<Utc>| WIDTH * (*at - *start).num_seconds() as f64 / span;
+    let y = |n: usize| HEIGHT - HEIGHT * n as f64 / max;
+
+    // each value is held until the next change, so draw a horizontal then a vertical line for each
+    let mut line = format!("0,{HEIGHT}");
+    let mut last = 0;
+    for (at, n) in points {
+        line += &format!(" {:.1},{:.1} {:.1},{:.1}", x(at), y(last), x(at), y(*n));
+        last = *n;
+    }
+
+    let time = |at: &DateTime<Utc>| at.with_timezone(&Local).format("%X").to_string();
+
+    maud::html! {
+        svg class="chart" viewBox=(format!("0 0 {WIDTH} {}", HEIGHT + 20.0)) role="img" {
+            polyline class="chart-line" points=(line) {}
+            line class="chart-axis" x1="0" y1=(HEIGHT) x2=(WIDTH) y2=(HEIGHT) {}
+            text class="chart-text" x="2" y="12" { "max " (max) }
+            text class="chart-text" x="0" y=(num(HEIGHT + 15.0)) { (time(start)) }
+            text class="chart-text" x=(WIDTH) y=(num(HEIGHT + 15.0)) text-anchor="end" { (time(end)) }
+        }
+    }
+}
+
+/// Renders the median and 90th percentile of a set of durations
+fn percentiles(name: &str, p: &Option<Percentiles>) -> maud::Markup {
+    maud::html! {
+        tr {
+            td { (name) }
+            @if let Some(p) = p {
+                td { (print_duration(p.median)) }
+                td { (print_duration(p.p90)) }
+                td { (p.count) }
+            } @else {
+                td colspan="3" { i { "no tickets yet" } }
+            }
+        }
+    }
+}
+
+/// Handler for the class's statistics page
+pub async fn view(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<maud::Markup, ClassError> {
+    let code = state.lookup_code(ip, &id)?;
+
+    // the statistics name students, so are only shown to staff
+    auth::require_teacher(&state, code, &headers)?.require(Permission::ManageTickets)?;
+
+    let stats = state.with_tickets(code, Stats::new)?;
+    let per_hour: Vec<_> = stats
+        .per_hour
+        .iter()
+        .map(|(at, n)| (at.format("%H:00").to_string(), *n))
+        .collect();
+
+    Ok(ui::base(
+        &format!("Statistics (Class {code})"),
+        maud::html! {
+            p { b { "Tickets opened: " } (stats.total) }
+
+            h2 { "Response times" }
+            table {
+                thead { tr { th { "" } th { "Median" } th { "90th percentile" } th { "Tickets" } } }
+                tbody {
+                    (percentiles("Time to claim", &stats.to_claim))
+                    (percentiles("Time to resolve", &stats.to_resolve))
+                }
+            }
+
+            h2 { "Tickets per hour" }
+            @if per_hour.is_empty() {
+                p { i { "No tickets yet." } }
+            } @else {
+                (bar_chart(&per_hour))
+            }
+
+            h2 { "Open tickets over time" }
+            @if stats.queue.is_empty() {
+                p { i { "No tickets yet." } }
+            } @else {
+                (step_chart(&stats.queue))
+            }
+
+            h2 { "Busiest students" }
+            @if stats.students.is_empty() {
+                p { i { "No tickets yet." } }
+            } @else {
+                (hbar_chart(&stats.students))
+            }
+
+            @if !stats.categories.is_empty() {
+                h2 { "Most common categories" }
+                (hbar_chart(&stats.categories))
+            }
+
+            a href=(ui::url(format!("/class/{code}/teacher"))) { "Back to the class." }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranks(minutes: &[i64]) -> Option<(i64, i64, usize)> {
+        let durations = minutes.iter().map(|&m| Duration::minutes(m)).collect();
+        Percentiles::new(durations).map(|p| (p.median.num_minutes(), p.p90.num_minutes(), p.count))
+    }
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        assert_eq!(ranks(&[]), None);
+        assert_eq!(ranks(&[7]), Some((7, 7, 1)));
+        assert_eq!(ranks(&[4, 1, 3, 2]), Some((2, 4, 4)));
+        assert_eq!(ranks(&[10, 3, 8, 1, 6, 2, 9, 4, 7, 5]), Some((5, 9, 10)));
+        assert_eq!(ranks(&[5; 11]), Some((5, 5, 11)));
+    }
+}
@@ -0,0 +1,222 @@
+//! This module contains the storage backends used to persist classes across restarts. No endpoints are
+//! defined in this module.
+//!
+//! The [`Storage`] trait is implemented by:
+//!   * [`SqliteStorage`] - stores classes in an SQLite database file (the default)
+//!   * [`MemoryStorage`] - keeps nothing, so all state is lost on shutdown (useful for testing)
+//!
+//! Each class's [`TicketList`] (its tickets, dismissed IDs and push subscribers) is stored as a single
+//! JSON document keyed by its [`ClassCode`]. Modified classes are written back by a background task (see
+//! [`AppState::flush`]), so a burst of changes to a class only writes it once. Any changes still waiting to be
+//! written when the server is shut down are written before it exits.
+//!
+//! [`AppState::flush`]: crate::state::AppState::flush
+
+use std::path::Path;
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection};
+
+use crate::state::ClassCode;
+use crate::ticket::TicketList;
+
+/// Error type for failures when reading or writing persistent state
+#[derive(thiserror::Error, Debug)]
+pub enum StorageError {
+    /// Error produced by the SQLite database
+    #[error("database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    /// Failed to (de)serialise a class
+    #[error("failed to (de)serialise class: {0}")]
+    Serde(#[from] serde_json::Error),
+    /// A stored class has an invalid code
+    #[error("invalid class code {0:?} in storage")]
+    InvalidCode(String),
+}
+
+/// A backend that [`AppState`](crate::state::AppState) persists its classes to
+pub trait Storage: Send + Sync {
+    /// Load every class stored in the backend
+    fn load(&self) -> Result<Vec<(ClassCode, TicketList)>, StorageError>;
+
+    /// Insert or replace the stored state of a class
+    fn save(&self, code: ClassCode, tickets: &TicketList) -> Result<(), StorageError>;
+
+    /// Remove a class from the backend
+    fn remove(&self, code: ClassCode) -> Result<(), StorageError>;
+}
+
+/// Open the storage backend at a given path. The special path `:memory:` produces a [`MemoryStorage`].
+pub fn open(path: impl AsRef<Path>) -> Result<Box<dyn Storage>, StorageError> {
+    let path = path.as_ref();
+
+    if path == Path::new(":memory:") {
+        Ok(Box::new(MemoryStorage))
+    } else {
+        Ok(Box::new(SqliteStorage::open(path)?))
+    }
+}
+
+/// Storage backend that doesn't persist anything
+pub struct MemoryStorage;
+
+impl Storage for MemoryStorage {
+    fn load(&self) -> Result<Vec<(ClassCode, TicketList)>, StorageError> {
+        Ok(vec![])
+    }
+
+    fn save(&self, _code: ClassCode, _tickets: &TicketList) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn remove(&self, _code: ClassCode) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+/// Migrations to bring the database's schema up to date. The database's `user_version` records how many
+/// have been applied.
+const MIGRATIONS: &[&str] = &[
+    // initial schema
+    "CREATE TABLE IF NOT EXISTS classes (
+        code INTEGER PRIMARY KEY,
+        data TEXT NOT NULL
+    );",
+    // class codes changed from 16-bit integers to strings; keep existing classes under their old hex codes
+    "CREATE TABLE classes_new (
+        code TEXT PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+    INSERT INTO classes_new SELECT printf('%04X', code), data FROM classes;
+    DROP TABLE classes;
+    ALTER TABLE classes_new RENAME TO classes;",
+];
+
+/// Apply any migrations that haven't yet been applied to the database
+fn migrate(conn: &mut Connection) -> Result<(), StorageError> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        // apply each migration atomically, along with the version bump
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+
+        tracing::info!(version = i + 1, "migrated database");
+    }
+
+    Ok(())
+}
+
+/// Storage backend that persists classes to an SQLite database
+pub struct SqliteStorage {
+    /// Connection to the database. `Connection` is not `Sync`, so we wrap it in a `Mutex`
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    /// Open (or create) the database at a given path, and ensure the schema exists
+    pub fn open(path: impl AsRef<Path>) -> Result<SqliteStorage, StorageError> {
+        let mut conn = Connection::open(path)?;
+        migrate(&mut conn)?;
+
+        Ok(SqliteStorage {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load(&self) -> Result<Vec<(ClassCode, TicketList)>, StorageError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT code, data FROM classes")?;
+
+        // read every row as a `(code, json)` pair
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // deserialise each class's ticket list
+        rows.into_iter()
+            .map(|(code, data)| {
+                let code = ClassCode::parse(&code).ok_or(StorageError::InvalidCode(code))?;
+                Ok((code, serde_json::from_str(&data)?))
+            })
+            .collect()
+    }
+
+    fn save(&self, code: ClassCode, tickets: &TicketList) -> Result<(), StorageError> {
+        let data = serde_json::to_string(tickets)?;
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            "INSERT OR REPLACE INTO classes (code, data) VALUES (?1, ?2)",
+            params![code.as_str(), data],
+        )?;
+
+        Ok(())
+    }
+
+    fn remove(&self, code: ClassCode) -> Result<(), StorageError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM classes WHERE code = ?1",
+            params![code.as_str()],
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a class with a single ticket in it
+    fn class() -> TicketList {
+        let mut tickets = TicketList::new();
+        tickets
+            .add_ticket("Alice", Some("stuck on part 2"), None::<&str>, None)
+            .unwrap();
+        tickets
+    }
+
+    #[test]
+    fn memory_storage_keeps_nothing() {
+        let storage = MemoryStorage;
+        let code = ClassCode::parse("ABCD").unwrap();
+
+        storage.save(code, &class()).unwrap();
+        assert!(storage.load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn sqlite_round_trip() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        let code = ClassCode::parse("ABCD").unwrap();
+        let tickets = class();
+
+        storage.save(code, &tickets).unwrap();
+        // saving again replaces the class rather than adding another
+        storage.save(code, &tickets).unwrap();
+
+        let loaded = storage.load().unwrap();
+        assert_eq!(loaded.len(), 1);
+
+        let (loaded_code, loaded) = &loaded[0];
+        assert!(*loaded_code == code);
+        assert_eq!(loaded.teacher_token(), tickets.teacher_token());
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded.tickets().next().unwrap().student(), "Alice");
+        assert_eq!(
+            loaded.tickets().next().unwrap().desc(),
+            Some("stuck on part 2")
+        );
+
+        storage.remove(code).unwrap();
+        assert!(storage.load().unwrap().is_empty());
+    }
+}
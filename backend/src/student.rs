@@ -1,14 +1,30 @@
 //! This module defines the endpoints for students accessing a class.
 //!
 //! This module is used to define the following endpoints:
-//!   * *GET*  `/class/{id}/ticket` ([`view`])
-//!   * *POST* `/class/{id}/ticket` ([`submit_ticket`])
+//!   * *GET*  `/class/{id}/student`                  ([`view`])
+//!   * *POST* `/class/{id}/student`                  ([`submit_ticket`])
+//!   * *GET*  `/class/{id}/ticket/{ticket}`          ([`ticket_view`])
+//!   * *POST* `/class/{id}/ticket/{ticket}/edit`     ([`edit_ticket`])
+//!   * *POST* `/class/{id}/ticket/{ticket}/withdraw` ([`withdraw_ticket`])
+//!   * *POST* `/class/{id}/ticket/{ticket}/message`  ([`post_message`])
 
-use axum::extract::{Form, Path, State};
+use axum::extract::{Form, Path, Query, State};
+use axum::http::HeaderMap;
+use axum::response::{AppendHeaders, IntoResponse, Redirect, Response};
 
 use serde::Deserialize;
 
-use crate::state::AppState;
+use crate::assets;
+use crate::auth::{self, Forbidden, Token};
+use crate::class::{self, ClassError};
+use crate::events::ClassEvent;
+use crate::push;
+use crate::ratelimit::ClientIp;
+use crate::state::{AppState, ClassCode};
+use crate::ticket::{
+    Actor, Added, Status, Ticket, TicketError, TicketId, TicketList, MAX_DESC_LEN, MAX_MESSAGE_LEN,
+    MAX_NAME_LEN,
+};
 use crate::ui;
 
 /// Data from ticket details form
@@ -18,13 +34,15 @@ pub struct FormData {
     pub student: String,
     /// An (optional) brief description of the ticket
     pub desc: String,
+    /// The category of the ticket, if the class has categories
+    pub category: Option<String>,
 }
 
 /// The form presented to students to open a ticket. Will `POST` the result to [`submit_ticket`] for
-/// a given class.
-pub fn form(id: u16) -> maud::Markup {
+/// a given class. If the class has categories, the student must choose one.
+pub fn form(code: ClassCode, categories: &[String]) -> maud::Markup {
     // the endpoint to send the form data to
-    let action = format!("/class/{id}/student");
+    let action = ui::url(format!("/class/{code}/student"));
 
     maud::html! {
         form class="t-form" action=(action) method="post" {
@@ -33,12 +51,25 @@ pub fn form(id: u16) -> maud::Markup {
 
                 div class="form-group" {
                     label for="student" { "Name: " }
-                    input name="student" type="text" required placeholder="John Doe" {}
+                    input name="student" type="text" maxlength=(MAX_NAME_LEN) required placeholder="John Doe" {}
+                }
+
+                @if !categories.is_empty() {
+                    div class="form-group" {
+                        label for="category" { "Category: " }
+                        select name="category" required {
+                            // the empty placeholder forces the student to make a choice
+                            option value="" disabled selected { "Choose a category" }
+                            @for category in categories {
+                                option value=(category) { (category) }
+                            }
+                        }
+                    }
                 }
 
                 div class="form-group" {
                     label for="desc" { "Description: " }
-                    input name="desc" type="text" placeholder="A brief description of your problem (optional)" {}
+                    input name="desc" type="text" maxlength=(MAX_DESC_LEN) placeholder="A brief description of your problem (optional)" {}
                 }
 
                 div class="form-group" {
@@ -49,23 +80,93 @@ pub fn form(id: u16) -> maud::Markup {
     }
 }
 
-/// Handler for any tickets submitted.
+/// The result of a student opening a ticket
+pub struct Opened {
+    /// The new ticket, or the student's existing ticket that the new one was merged into
+    pub ticket: Ticket,
+    /// The token that allows the student to change the ticket. Not given when the ticket was merged into one
+    /// that was only recognised by the student's name, as it may belong to somebody else
+    pub token: Option<Token>,
+    /// Whether the ticket was merged into the student's existing ticket
+    pub merged: bool,
+}
+
+/// Add a ticket to a class, and notify the teacher. If the student already has an open ticket (recognised
+/// by their `session` or name), the class's [`Duplicates`](crate::ticket::Duplicates) setting decides
+/// whether it is refused or merged. Produces an error if the category isn't valid for the class, or the
+/// ticket is refused.
+pub fn open_ticket(
+    state: &AppState,
+    code: ClassCode,
+    student: &str,
+    desc: Option<String>,
+    category: Option<String>,
+    session: Option<&str>,
+) -> Result<Opened, TicketError> {
+    // add a ticket to the classes' list
+    let (added, ticket, token) = state.try_with_tickets_mut(code, |t| {
+        let added = t.add_ticket(student, desc.as_ref(), category.as_ref(), session)?;
+        let ticket = t.get(added.id()).cloned().unwrap();
+        let token = t.student_token(added.id()).cloned().unwrap();
+        Ok::<_, TicketError>((added, ticket, token))
+    })?;
+
+    if let Added::Merged { same_session, .. } = added {
+        // the teacher was already notified of the ticket, so just show the new description
+        state.publish(code, ClassEvent::TicketUpdated(ticket.clone()));
+
+        return Ok(Opened {
+            ticket,
+            token: same_session.then_some(token),
+            merged: true,
+        });
+    }
+
+    // update the teacher's view
+    state.publish(code, ClassEvent::TicketAdded(ticket.clone()));
+    state.metrics().record_created(code);
+
+    // notify the teacher's devices of the new ticket; failures are logged, and don't affect the student
+    // use the ticket's name and description, which have been shortened to fit in a notification
+    let title = match ticket.category() {
+        Some(category) => format!("{} needs help ({category})", ticket.student()),
+        None => format!("{} needs help", ticket.student()),
+    };
+    let msg = push::Notification {
+        title,
+        body: ticket
+            .desc()
+            .unwrap_or("No description provided")
+            .to_string(),
+    };
+
+    push::notify(state, code, msg);
+
+    Ok(Opened {
+        ticket,
+        token: Some(token),
+        merged: false,
+    })
+}
+
+/// Handler for any tickets submitted. The student is given a session cookie, so that they can be recognised
+/// if they open another ticket.
 #[axum::debug_handler]
 pub async fn submit_ticket(
     State(state): State<AppState>,
-    Path(class_id): Path<u16>,
-    Form(FormData { student, desc }): Form<FormData>,
-) -> maud::Markup {
-    let Ok(code) = state.get_code(class_id) else {
-        // class doesn't exist, present an error and prompt them to join a class
-        return ui::base(
-            "Unknown Class",
-            maud::html! {
-                p { "Unknown class ID " (class_id) "." }
-                a href="/join-class" { "Go back." };
-            },
-        );
-    };
+    Path(class_id): Path<String>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    Form(FormData {
+        student,
+        desc,
+        category,
+    }): Form<FormData>,
+) -> Result<impl IntoResponse, Response> {
+    // if the class doesn't exist, present an error and prompt them to join a class
+    let code = state
+        .lookup_code(ip, &class_id)
+        .map_err(class::lookup_error)?;
 
     let desc = if desc.trim().is_empty() {
         // whitespace-trimmed description is empty, produce desc of `None`
@@ -74,38 +175,363 @@ pub async fn submit_ticket(
         Some(desc)
     };
 
-    // add a ticket to the classes' list
-    let (sub, id) = state.with_tickets_mut(code, |t| {
-        (t.subscriber(), t.add_ticket(&student, desc.as_ref()))
-    });
+    // the student's existing session, or a new one if this is their first ticket in the class
+    let session = auth::get_session(&headers)
+        .map(str::to_string)
+        .unwrap_or_else(|| Token::generate().to_string());
 
-    // if let Some(sub) = sub {
-    //     let vapid = state.vapid();
-    //     let msg = sub
-    //         .with_vapid(&vapid, "mailto:jcbsnclr@outlook.com")
-    //         .build("new ticket")?
-    //         .map(|body| body.into());
+    let opened =
+        open_ticket(&state, code, &student, desc, category, Some(&session)).map_err(|e| {
+            let existing = match e {
+                // the class was closed while the ticket was being opened
+                TicketError::UnknownClass(e) => return class::lookup_error(e.into()),
+                TicketError::Duplicate(id) => {
+                    Some(ui::url(format!("/class/{code}/ticket/{}", id.as_usize())))
+                }
+                _ => None,
+            };
 
-    //     let client = reqwest::Client::new();
+            // the student chose a category that has since been removed, didn't choose one at all, or already
+            // has a ticket open
+            ui::base(
+                "Can't Open Ticket",
+                maud::html! {
+                    p { (e) "." }
+                    @if let Some(existing) = existing {
+                        p { a href=(existing) { "View your open ticket." } }
+                    }
+                    a href=(ui::url(format!("/class/{code}/student"))) { "Go back." }
+                },
+            )
+            .into_response()
+        })?;
+    let id = opened.ticket.id();
 
-    //     // let req = reqwest::RequestBuilder::
+    // both cookies use the same header, so must be appended rather than replacing each other
+    let cookies = auth::session_cookie(code, &session).into_iter().chain(
+        opened
+            .token
+            .map(|token| auth::ticket_cookie(code, id, &token))
+            .into_iter()
+            .flatten(),
+    );
 
-    //     client.execute(msg);
-    // }
+    // send the user to the status page for their ticket, with a cookie allowing them to change it
+    Ok((
+        AppendHeaders(cookies),
+        Redirect::to(&ui::url(format!("/class/{code}/ticket/{}", id.as_usize()))),
+    ))
+}
 
-    // present user with message to indicate success
-    ui::base(
-        "Open Ticket",
+/// Format a duration as a rough number of minutes
+fn print_minutes(duration: chrono::Duration) -> String {
+    // round up, so that we never claim a wait of 0 minutes
+    match (duration.num_seconds() + 59) / 60 {
+        0 | 1 => "about a minute".to_string(),
+        mins => format!("about {mins} minutes"),
+    }
+}
+
+/// Renders the current status of a ticket, as shown to the student that opened it
+pub fn status(tickets: &TicketList, id: TicketId) -> maud::Markup {
+    let Some(ticket) = tickets.get(id) else {
+        return maud::html! { p { "Unknown ticket " (id) "." } };
+    };
+
+    let position = tickets.position(id);
+    // the member of staff that claimed or started helping with the ticket
+    let helper = match ticket.last_transition().map(|t| &t.actor) {
+        Some(Actor::Staff(name)) => name.clone(),
+        _ => "A teacher".to_string(),
+    };
+
+    maud::html! {
+        @match (ticket.status(), position) {
+            (Status::Open, Some(position)) => {
+                div class="terminal-alert terminal-alert-primary" {
+                    "You are number " b { (position) } " in the queue."
+                }
+                p {
+                    b { "Estimated wait: " }
+                    @if let Some(wait) = tickets.estimate_wait(position) {
+                        (print_minutes(wait))
+                    } @else {
+                        i { "not enough tickets resolved yet" }
+                    }
+                }
+            }
+            (Status::Claimed, _) => {
+                div class="terminal-alert terminal-alert-primary" {
+                    (helper) " has claimed your ticket, and will be with you soon."
+                }
+            }
+            (Status::InProgress, _) => {
+                div class="terminal-alert terminal-alert-primary" {
+                    (helper) " is helping you now."
+                }
+            }
+            (Status::Resolved, _) => {
+                div class="terminal-alert" { "Your ticket has been resolved." }
+            }
+            (Status::Cancelled, _) if ticket.last_transition().map(|t| &t.actor) == Some(&Actor::Student) => {
+                div class="terminal-alert" { "You withdrew your ticket." }
+            }
+            (status, _) => {
+                div class="terminal-alert terminal-alert-error" { "Your ticket was " (status) "." }
+            }
+        }
+
+        @if let Some(category) = ticket.category() {
+            p { b { "Category: " } (category) }
+        }
+
+        p {
+            b { "Description: " }
+            @if let Some(desc) = ticket.desc() {
+                (desc)
+            } @else {
+                i { "No description provided" }
+            }
+        }
+    }
+}
+
+/// The URL query arguments to the ticket status page
+#[derive(Deserialize)]
+pub struct StatusArgs {
+    /// If `Some(true)`, then return only the ticket's status, otherwise return the whole page
+    raw: Option<bool>,
+    /// If `Some(true)` along with `raw`, then return only the ticket's thread of messages instead
+    thread: Option<bool>,
+}
+
+/// Handler for a student's view of the status of their ticket. The status will update live as the
+/// teacher works through the queue. Only the student that opened the ticket, and the class's staff, may view
+/// it.
+pub async fn ticket_view(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path((class_id, id)): Path<(String, TicketId)>,
+    Query(args): Query<StatusArgs>,
+    headers: HeaderMap,
+) -> Result<maud::Markup, ClassError> {
+    let code = state.lookup_code(ip, &class_id)?;
+
+    // the ticket describes the student's problem, so is hidden from the rest of the class
+    auth::require_viewer(&state, code, id, &headers)?;
+
+    // the thread is only shown to the student that opened the ticket
+    let thread = state
+        .with_tickets(code, |t| t.get(id).map(Ticket::thread))?
+        .filter(|_| auth::require_owner(&state, code, id, &headers).is_ok());
+
+    if args.raw.unwrap_or(false) {
+        state.metrics().record_partial("ticket");
+
+        // only send the status or thread alone (used to update page dynamically)
+        return match args.thread {
+            Some(true) => Ok(thread.unwrap_or_else(|| maud::html! {})),
+            _ => Ok(state.with_tickets(code, |t| status(t, id))?),
+        };
+    }
+
+    let status = state.with_tickets(code, |t| status(t, id))?;
+    let open = state.with_tickets(code, |t| {
+        t.get(id).map(|t| t.status().is_open()).unwrap_or(false)
+    })?;
+
+    // the student that opened the ticket may change it while it is still open
+    let desc = state.with_tickets(code, |t| {
+        t.get(id)
+            .filter(|t| t.status().is_open())
+            .map(|t| t.desc().unwrap_or_default().to_string())
+    })?;
+    let desc = desc.filter(|_| auth::require_owner(&state, code, id, &headers).is_ok());
+    let action = ui::url(format!("/class/{code}/ticket/{}", id.as_usize()));
+
+    Ok(ui::base(
+        &format!("Ticket {id} (Class {code})"),
         maud::html! {
-            div class="terminal-alert terminal-alert-primary" {
-                "Success (ticket " (id) " )"
+            // filled with the ticket's status, and updated dynamically via JS
+            div id="ticket-status" { (status) }
+
+            @if let Some(desc) = desc {
+                form class="t-form" action=(format!("{action}/edit")) method="post" {
+                    fieldset {
+                        legend { "Change ticket" }
+
+                        div class="form-group" {
+                            label for="desc" { "Description: " }
+                            input name="desc" type="text" maxlength=(MAX_DESC_LEN) value=(desc) placeholder="A brief description of your problem (optional)" {}
+                        }
+
+                        div class="form-group" {
+                            input type="submit" value="Update" class="btn btn-default" {}
+                        }
+                    }
+                }
+
+                form action=(format!("{action}/withdraw")) method="post" {
+                    input type="submit" value="Withdraw ticket" class="btn btn-error btn-ghost" {}
+                }
             }
-            (form(class_id))
+
+            @if let Some(thread) = thread {
+                h2 { "Messages" }
+                // filled with the ticket's thread, and updated dynamically via JS
+                div id="ticket-thread" { (thread) }
+
+                @if open {
+                    form class="t-form" action=(format!("{action}/message")) method="post" {
+                        div class="form-group" {
+                            textarea name="text" rows="2" maxlength=(MAX_MESSAGE_LEN) required
+                                placeholder="Send a message to your teacher" {}
+                        }
+                        div class="form-group" {
+                            input type="submit" value="Send" class="btn btn-default btn-ghost" {}
+                        }
+                    }
+                }
+            }
+
+            a href=(ui::url(format!("/class/{code}/student"))) { "Open another ticket." }
+
+            // load script to listen for changes to the ticket's status
+            script src=(ui::url(assets::path("ticket-view.js")))
+                classid=(code)
+                ticketid=(id.as_usize())
+                basepath=(ui::base_path()) {}
+        },
+    ))
+}
+
+/// Presents the ticket submission form to the user, if the class exists
+pub async fn view(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    ClientIp(ip): ClientIp,
+) -> Result<maud::Markup, ClassError> {
+    let code = state.lookup_code(ip, &id)?;
+
+    let categories = state.with_tickets(code, |t| t.categories().to_vec())?;
+
+    Ok(ui::base("Ask for Help", form(code, &categories)))
+}
+
+/// Data from the ticket edit form
+#[derive(Debug, Clone, Deserialize)]
+pub struct EditData {
+    /// The new (optional) description of the ticket
+    pub desc: String,
+}
+
+/// Present an error that occurred when the student tried to change their ticket
+fn ticket_error(code: ClassCode, id: TicketId, e: TicketError) -> Response {
+    // the class was closed in the meantime
+    if let TicketError::UnknownClass(e) = e {
+        return class::lookup_error(e.into());
+    }
+
+    ui::base(
+        "Can't Change Ticket",
+        maud::html! {
+            p { (e) "." }
+            a href=(ui::url(format!("/class/{code}/ticket/{}", id.as_usize()))) { "Go back." }
         },
     )
+    .into_response()
+}
+
+/// Handler for the student editing the description of their ticket
+pub async fn edit_ticket(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path((class_id, id)): Path<(String, TicketId)>,
+    headers: HeaderMap,
+    Form(EditData { desc }): Form<EditData>,
+) -> Result<Redirect, Response> {
+    let code = state
+        .lookup_code(ip, &class_id)
+        .map_err(class::lookup_error)?;
+
+    // only the student that opened the ticket may edit it
+    auth::require_owner(&state, code, id, &headers).map_err(Forbidden::into_response)?;
+
+    let desc = if desc.trim().is_empty() {
+        None
+    } else {
+        Some(desc)
+    };
+
+    let ticket = state
+        .try_with_tickets_mut(code, |t| {
+            t.edit(id, desc.as_ref())?;
+            Ok::<_, TicketError>(t.get(id).cloned().unwrap())
+        })
+        .map_err(|e| ticket_error(code, id, e))?;
+
+    // show the new description in the teacher's view
+    state.publish(code, ClassEvent::TicketUpdated(ticket));
+
+    Ok(Redirect::to(&ui::url(format!(
+        "/class/{code}/ticket/{}",
+        id.as_usize()
+    ))))
+}
+
+/// Handler for the student withdrawing their ticket, as they no longer need help
+pub async fn withdraw_ticket(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path((class_id, id)): Path<(String, TicketId)>,
+    headers: HeaderMap,
+) -> Result<Redirect, Response> {
+    let code = state
+        .lookup_code(ip, &class_id)
+        .map_err(class::lookup_error)?;
+
+    // only the student that opened the ticket may withdraw it
+    auth::require_owner(&state, code, id, &headers).map_err(Forbidden::into_response)?;
+
+    // cancel the ticket, removing it from the teacher's view
+    state
+        .transition(code, id, Status::Cancelled, Actor::Student, None)
+        .map_err(|e| ticket_error(code, id, e))?;
+
+    Ok(Redirect::to(&ui::url(format!(
+        "/class/{code}/ticket/{}",
+        id.as_usize()
+    ))))
 }
 
-/// Presents the ticket submission form to the user. Does no additional processing
-pub async fn view(Path(id): Path<u16>) -> maud::Markup {
-    ui::base("Ask for Help", form(id))
+/// Data from the message form
+#[derive(Debug, Clone, Deserialize)]
+pub struct MessageData {
+    /// The contents of the message
+    pub text: String,
+}
+
+/// Handler for the student posting a message to their ticket's thread
+pub async fn post_message(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path((class_id, id)): Path<(String, TicketId)>,
+    headers: HeaderMap,
+    Form(MessageData { text }): Form<MessageData>,
+) -> Result<Redirect, Response> {
+    let code = state
+        .lookup_code(ip, &class_id)
+        .map_err(class::lookup_error)?;
+
+    // only the student that opened the ticket may talk in its thread
+    auth::require_owner(&state, code, id, &headers).map_err(Forbidden::into_response)?;
+
+    state
+        .post_message(code, id, Actor::Student, &text)
+        .map_err(|e| ticket_error(code, id, e))?;
+
+    Ok(Redirect::to(&ui::url(format!(
+        "/class/{code}/ticket/{}",
+        id.as_usize()
+    ))))
 }
@@ -1,70 +1,641 @@
 //! This module defines the endpoints for teachers viewing open tickets.
 //!
-//! This module is used to defines the endpoint `/class/{id}/teacher`, which accepts two arguments in the
+//! This module is used to defines the endpoint `/class/{id}/teacher`, which accepts one argument in the
 //! query part of the URL:
 //!   * `raw: bool` - if `true`, return only the rendered list of tickets, else return skeleton of the UI
-//!   * `dismissed: TicketId` - if provided, then dismiss the given ticket
+//!
+//! Staff act on tickets via *POST* `/class/{id}/ticket/{ticket}/{action}` ([`perform`]), where `action` is
+//! one of [`Action`], optionally giving a `summary` of what the problem was when resolving or dismissing it.
+//!
+//! This module also defines the endpoint *POST* `/class/{id}/close` ([`close_class`]), which ends the class,
+//! *POST* `/class/{id}/categories` ([`set_categories`]), which changes the class's ticket categories,
+//! *POST* `/class/{id}/policy` ([`set_policy`]), which changes the order tickets are helped in, and
+//! *POST* `/class/{id}/duplicates` ([`set_duplicates`]), which changes what happens when a student opens a
+//! second ticket.
+//!
+//! Staff can see the full history of a ticket, including any private notes and the summary given when it
+//! was closed, at *GET* `/class/{id}/ticket/{ticket}/history` ([`history`]), and add notes via *POST*
+//! `/class/{id}/ticket/{ticket}/note` ([`add_note`]). Neither is shown to students. The history page also
+//! shows the ticket's thread of messages with the student, marking it as read, and staff can reply via
+//! *POST* `/class/{id}/ticket/{ticket}/reply` ([`reply`]).
+//!
+//! The teacher's view lists the devices subscribed to push notifications, each of which can be removed via
+//! *POST* `/class/{id}/unsubscribe` (see [`crate::class::unsubscribe`]).
+//!
+//! These endpoints are only accessible to the class's staff (see [`auth::require_teacher`]), and some actions
+//! are restricted by the member's role (see [`staff::Role::can`]).
 
 use serde::Deserialize;
 
-use axum::extract::{Path, Query, State};
+use chrono::{DateTime, Utc};
+
+use axum::extract::{Form, Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::Redirect;
 
 use maud::Render;
 
-use crate::state::AppState;
-use crate::ticket::TicketId;
+use crate::assets;
+use crate::auth;
+use crate::class::ClassError;
+use crate::events::ClassEvent;
+use crate::push::Subscriber;
+use crate::queue::Policy;
+use crate::ratelimit::ClientIp;
+use crate::staff::{self, Permission, Staff};
+use crate::state::{AppState, ClassCode};
+use crate::ticket::{
+    Actor, Duplicates, Note, Status, Ticket, TicketError, TicketId, Transition, MAX_MESSAGE_LEN,
+    MAX_NOTE_LEN,
+};
 use crate::ui;
 
+/// An action the teacher can perform on a ticket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Mark the ticket as being dealt with by the teacher
+    Claim,
+    /// Mark the ticket as currently being helped with
+    Start,
+    /// Mark the student as having been helped
+    Resolve,
+    /// Close the ticket without helping the student
+    Dismiss,
+    /// Move the ticket to the front of the queue, when using the priority policy
+    Bump,
+    /// Undo a bump
+    Unbump,
+}
+
+impl Action {
+    /// The status a ticket moves to when the action is performed, if it changes the ticket's status
+    pub fn status(&self) -> Option<Status> {
+        match self {
+            Action::Claim => Some(Status::Claimed),
+            Action::Start => Some(Status::InProgress),
+            Action::Resolve => Some(Status::Resolved),
+            Action::Dismiss => Some(Status::Cancelled),
+            Action::Bump | Action::Unbump => None,
+        }
+    }
+
+    /// Perform the action on a ticket on behalf of a member of staff, and notify anybody listening to the
+    /// class. The member's permissions should already have been checked. The `summary` is only kept when
+    /// resolving or dismissing the ticket. Returns the updated ticket.
+    pub fn perform(
+        &self,
+        state: &AppState,
+        code: ClassCode,
+        id: TicketId,
+        member: &Staff,
+        summary: Option<&str>,
+    ) -> Result<Ticket, TicketError> {
+        match self.status() {
+            Some(status) => state.transition(code, id, status, Actor::staff(member), summary),
+            None => state.bump(code, id, *self == Action::Bump),
+        }
+    }
+
+    /// The permission a member of staff needs to perform the action
+    pub fn permission(&self) -> Permission {
+        match self {
+            Action::Dismiss => Permission::DismissTickets,
+            _ => Permission::ManageTickets,
+        }
+    }
+}
+
 /// The URL query arguments to the teacher view
 #[derive(Deserialize)]
 pub struct TeacherArgs {
-    /// If `Some(id)`, then we dismiss the specified ticket
-    dismissed: Option<TicketId>,
     /// If `Some(true)`, then return list of tickets, otherwise just return UI skeleton
     raw: Option<bool>,
 }
 
-/// Handler for the teacher's list of tickets. Teacher can dismiss tickets, view will automatically refresh.
+/// Renders the list of devices subscribed to push notifications, each with a button to unsubscribe it
+fn devices(code: ClassCode, subs: &[Subscriber]) -> maud::Markup {
+    let action = ui::url(format!("/class/{code}/unsubscribe"));
+
+    maud::html! {
+        @if subs.is_empty() {
+            p { i { "No devices are subscribed to notifications." } }
+        }
+        ul {
+            @for sub in subs {
+                li {
+                    b { (sub.label()) } ", subscribed " (sub.created().format("%c")) ", "
+                    @if let Some(last) = sub.last_success() {
+                        "last notified " (last.format("%c"))
+                    } @else {
+                        "not yet notified"
+                    }
+                    " "
+                    form class="inline-form" action=(action) method="post" {
+                        input type="hidden" name="endpoint" value=(sub.endpoint()) {}
+                        input type="submit" value="Remove" class="btn btn-error btn-ghost" {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Renders the list of resolved and cancelled tickets, each linking to its history
+fn closed_list(code: ClassCode, tickets: &[Ticket]) -> maud::Markup {
+    maud::html! {
+        @if tickets.is_empty() {
+            p { i { "No tickets have been closed yet." } }
+        }
+        ul {
+            @for ticket in tickets {
+                li {
+                    a href=(ui::url(format!("/class/{code}/ticket/{}/history", ticket.id().as_usize()))) {
+                        (ticket.id()) " " (ticket.student())
+                    }
+                    " (" (ticket.status()) ")"
+                    @if let Some(summary) = ticket.summary() {
+                        ": " (summary)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Handler for the teacher's list of tickets. Teacher can claim, resolve and dismiss tickets (see
+/// [`perform`]), view will automatically refresh.
 pub async fn ticket_list(
     State(state): State<AppState>,
-    Path(id): Path<u16>,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<String>,
     Query(args): Query<TeacherArgs>,
-) -> maud::Markup {
-    let Ok(code) = state.get_code(id) else {
-        // class not found, prompt to create class
-        return ui::base(
-            "Unknown Class",
-            maud::html! {
-                p { "Unknown class ID " (id) "." }
-                a href="/create-class" { "Create class." };
-            },
-        );
-    };
+    headers: HeaderMap,
+) -> Result<maud::Markup, ClassError> {
+    let code = state.lookup_code(ip, &id)?;
 
-    if let Some(ticket) = args.dismissed {
-        // user has requested to dismiss a ticket
-        state.with_tickets_mut(code, |t| t.dismiss(ticket));
-    }
+    // only the class's staff may view tickets
+    let member = auth::require_teacher(&state, code, &headers)?;
 
     // render the list of tickets to HTML
-    let list = state.with_tickets(code, |t| t.render());
+    let list = state.with_tickets(code, |t| t.render())?;
 
     if !args.raw.unwrap_or(false) {
+        // link the member can open on their other devices to gain access to the class
+        let admin_link = ui::url(format!("/class/{code}/admin?token={}", member.token()));
+        let subs = state.with_tickets(code, |t| t.subscribers().to_vec())?;
+        let (staff_panel, staff_count) = state.with_tickets(code, |t| {
+            let panel = staff::panel(code, &t.owner(), t.staff(), &member);
+            (panel, t.staff().members().len() + 1)
+        })?;
+        let owner = member.role().can(Permission::ManageClass);
+        let can_export = member.role().can(Permission::ExportTickets);
+        let (categories, policy, duplicates) = state.with_tickets(code, |t| {
+            (t.categories().to_vec(), t.policy(), t.duplicates())
+        })?;
+        let closed = state.with_tickets(code, |t| {
+            let mut closed: Vec<_> = t
+                .tickets()
+                .filter(|t| !t.status().is_open())
+                .cloned()
+                .collect();
+            // most recently opened first
+            closed.reverse();
+            closed
+        })?;
+
         // present the base UI
-        ui::base(
+        Ok(ui::base(
             &format!("Open Tickets (Class {code})"),
             maud::html! {
                 btn class="btn btn-primary btn-ghost" onclick="subscribe()" { "Subscribe" }
+                btn class="btn btn-default btn-ghost" onclick="unsubscribe()" { "Unsubscribe" }
+                @if owner {
+                    form class="inline-form" action=(ui::url(format!("/class/{code}/close"))) method="post"
+                        onsubmit="return confirm('End the class? All tickets will be deleted.')" {
+                        input type="submit" value="End class" class="btn btn-error btn-ghost" {}
+                    }
+                }
+                details {
+                    summary { "Admin link" }
+                    p { "Open this link to access the class on your other devices. Keep it secret from everyone else!" }
+                    a href=(admin_link) { (admin_link) }
+                }
+                details {
+                    summary { "Staff (" (staff_count) ")" }
+                    (staff_panel)
+                }
+                details {
+                    summary { "Notifications (" (subs.len()) " devices)" }
+                    (devices(code, &subs))
+                }
+                p { a href=(ui::url(format!("/class/{code}/stats"))) { "View statistics for this class." } }
+                details {
+                    summary { "Closed tickets (" (closed.len()) ")" }
+                    (closed_list(code, &closed))
+                }
+                @if can_export {
+                    details {
+                        summary { "Export tickets" }
+                        // see `export::export`; the dates are optional
+                        form class="t-form" action=(ui::url(format!("/class/{code}/export"))) method="get" {
+                            div class="form-group" {
+                                label for="from" { "Opened from: " }
+                                input name="from" type="date" {}
+                            }
+                            div class="form-group" {
+                                label for="to" { "Opened until: " }
+                                input name="to" type="date" {}
+                            }
+                            div class="form-group" {
+                                label for="format" { "Format: " }
+                                select name="format" {
+                                    option value="csv" selected { "CSV (spreadsheet)" }
+                                    option value="json" { "JSON" }
+                                }
+                            }
+                            div class="form-group" {
+                                input type="submit" value="Download" class="btn btn-default btn-ghost" {}
+                            }
+                        }
+                    }
+                }
+                @if owner {
+                    details {
+                        summary { "Categories (" (categories.len()) ")" }
+                        form class="t-form" action=(ui::url(format!("/class/{code}/categories"))) method="post" {
+                            div class="form-group" {
+                                label for="categories" { "Categories (one per line): " }
+                                textarea name="categories" rows="5" { (categories.join("\n")) }
+                            }
+                            div class="form-group" {
+                                input type="submit" value="Save" class="btn btn-default btn-ghost" {}
+                            }
+                        }
+                    }
+                    details {
+                        summary { "Duplicate tickets" }
+                        form class="inline-form" action=(ui::url(format!("/class/{code}/duplicates"))) method="post" {
+                            label for="duplicates" { "When a student opens a second ticket: " }
+                            select name="duplicates" onchange="this.form.submit()" {
+                                @for d in Duplicates::ALL {
+                                    option value=(d.name()) selected[d == duplicates] { (d) }
+                                }
+                            }
+                        }
+                    }
+                }
                 hr {}
+                @if owner {
+                    form class="inline-form" action=(ui::url(format!("/class/{code}/policy"))) method="post" {
+                        label for="policy" { "Queue order: " }
+                        select name="policy" onchange="this.form.submit()" {
+                            @for p in Policy::ALL {
+                                option value=(p.name()) selected[p == policy] { (p) }
+                            }
+                        }
+                    }
+                } @else {
+                    p { b { "Queue order: " } (policy) }
+                }
+                @if !categories.is_empty() {
+                    // chips to only show tickets of one category, handled by `teacher-view.js`
+                    div id="category-filter" {
+                        button class="btn btn-primary category-chip" data-category="" { "All" }
+                        @for category in &categories {
+                            button class="btn btn-default category-chip" data-category=(category) { (category) }
+                        }
+                    }
+                }
                 // creates div for the ticket list, will be dynamically filled via JS/AJAX
-                div id="ticket-list" {}
+                // the policy is used to only show the bump buttons when they have an effect
+                div id="ticket-list" data-policy=(policy.name()) {}
 
                 // load script to dynamically refresh contents of `#ticket-list`, will refresh on load
-                script src="/static/teacher-view.js" classid=(code.as_u16()) {}
+                script src=(ui::url(assets::path("teacher-view.js"))) classid=(code) basepath=(ui::base_path()) {}
             },
-        )
+        ))
     } else {
         // only send the list alone (used to update list dynamically)
-        list
+        state.metrics().record_partial("teacher");
+        Ok(list)
+    }
+}
+
+/// Contains the data submitted when a member of staff performs an action on a ticket
+#[derive(Deserialize)]
+pub struct ActionData {
+    /// What the problem turned out to be, when resolving or dismissing the ticket
+    summary: Option<String>,
+}
+
+/// Handler for a member of staff performing an action on a ticket from the ticket list (via
+/// `teacher-view.js`), which their role may not allow. The updated ticket is sent to every member of staff's
+/// list via the class's event stream.
+pub async fn perform(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path((class_id, id, action)): Path<(String, TicketId, Action)>,
+    headers: HeaderMap,
+    Form(data): Form<ActionData>,
+) -> Result<StatusCode, ClassError> {
+    let code = state.lookup_code(ip, &class_id)?;
+
+    let member = auth::require_teacher(&state, code, &headers)?;
+    member.require(action.permission())?;
+
+    match action.perform(&state, code, id, &member, data.summary.as_deref()) {
+        Ok(_) => Ok(StatusCode::NO_CONTENT),
+        Err(TicketError::UnknownClass(e)) => Err(e.into()),
+        Err(e) => {
+            // e.g. another member of staff closed the ticket first
+            tracing::warn!(%code, member = member.name(), error = %e, "failed to update ticket");
+            Ok(StatusCode::CONFLICT)
+        }
     }
 }
+
+/// Handler for the teacher ending a class. All of the class's tickets are deleted.
+pub async fn close_class(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<maud::Markup, ClassError> {
+    let code = state.lookup_code(ip, &id)?;
+
+    // only the class's owner may end the class
+    auth::require_teacher(&state, code, &headers)?.require(Permission::ManageClass)?;
+
+    // the class may have expired in the meantime, in which case it's already closed
+    let _ = state.close_class(code);
+
+    Ok(ui::base(
+        "Class Ended",
+        maud::html! {
+            p { "The class has ended, and all of its tickets have been deleted." }
+            a href=(ui::url("/create-class")) { "Create another class." }
+        },
+    ))
+}
+
+/// Contains the data submitted when the teacher changes the class's categories
+#[derive(Deserialize)]
+pub struct CategoryData {
+    /// The categories students choose from when opening a ticket, one per line
+    categories: String,
+}
+
+/// Handler for the teacher changing the categories students choose from. Existing tickets keep their
+/// category.
+pub async fn set_categories(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Form(data): Form<CategoryData>,
+) -> Result<Redirect, ClassError> {
+    let code = state.lookup_code(ip, &id)?;
+
+    // categories are a setting of the class, so only the owner may change them
+    auth::require_teacher(&state, code, &headers)?.require(Permission::ManageClass)?;
+
+    state.with_tickets_mut(code, |t| t.set_categories(data.categories.lines()))?;
+
+    Ok(Redirect::to(&ui::url(format!("/class/{id}/teacher"))))
+}
+
+/// Contains the data submitted when the teacher changes the class's queue policy
+#[derive(Deserialize)]
+pub struct PolicyData {
+    /// The new policy
+    policy: Policy,
+}
+
+/// Handler for the teacher changing the order tickets are helped in
+pub async fn set_policy(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Form(data): Form<PolicyData>,
+) -> Result<Redirect, ClassError> {
+    let code = state.lookup_code(ip, &id)?;
+
+    // the policy is a setting of the class, so only the owner may change it
+    auth::require_teacher(&state, code, &headers)?.require(Permission::ManageClass)?;
+
+    state.with_tickets_mut(code, |t| t.set_policy(data.policy))?;
+    state.publish(code, ClassEvent::Reordered);
+
+    Ok(Redirect::to(&ui::url(format!("/class/{id}/teacher"))))
+}
+
+/// Contains the data submitted when the teacher changes how duplicate tickets are handled
+#[derive(Deserialize)]
+pub struct DuplicatesData {
+    /// The new setting
+    duplicates: Duplicates,
+}
+
+/// Handler for the teacher changing what happens when a student opens a ticket while they already have one
+/// open. Tickets that are already open are unaffected.
+pub async fn set_duplicates(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Form(data): Form<DuplicatesData>,
+) -> Result<Redirect, ClassError> {
+    let code = state.lookup_code(ip, &id)?;
+
+    // this is a setting of the class, so only the owner may change it
+    auth::require_teacher(&state, code, &headers)?.require(Permission::ManageClass)?;
+
+    state.with_tickets_mut(code, |t| t.set_duplicates(data.duplicates))?;
+
+    Ok(Redirect::to(&ui::url(format!("/class/{id}/teacher"))))
+}
+
+/// An entry in a ticket's history, which are shown in the order they happened
+enum HistoryEntry<'a> {
+    Transition(&'a Transition),
+    Note(&'a Note),
+}
+
+impl HistoryEntry<'_> {
+    fn timestamp(&self) -> DateTime<Utc> {
+        match self {
+            HistoryEntry::Transition(t) => t.timestamp,
+            HistoryEntry::Note(n) => n.timestamp,
+        }
+    }
+}
+
+/// Handler for a member of staff viewing the full history of a ticket, including its private notes. Notes
+/// can be added from this page.
+pub async fn history(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path((class_id, id)): Path<(String, TicketId)>,
+    headers: HeaderMap,
+) -> Result<maud::Markup, ClassError> {
+    let code = state.lookup_code(ip, &class_id)?;
+
+    // notes are private to the class's staff
+    auth::require_teacher(&state, code, &headers)?;
+
+    // the member of staff can now see the whole thread; the ticket may not exist, which is handled below
+    let _ = state.mark_read(code, id);
+
+    let back = ui::url(format!("/class/{code}/teacher"));
+    let Some(ticket) = state.with_tickets(code, |t| t.get(id).cloned())? else {
+        return Ok(ui::base(
+            "Unknown Ticket",
+            maud::html! {
+                p { "Unknown ticket " (id) "." }
+                a href=(back) { "Go back." }
+            },
+        ));
+    };
+
+    let mut entries: Vec<_> = ticket
+        .history()
+        .iter()
+        .map(HistoryEntry::Transition)
+        .chain(ticket.notes().iter().map(HistoryEntry::Note))
+        .collect();
+    // the sort is stable, so a note written at the same time as a change stays after it
+    entries.sort_by_key(HistoryEntry::timestamp);
+
+    Ok(ui::base(
+        &format!("Ticket {id} (Class {code})"),
+        maud::html! {
+            p { b { "Student: " } (ticket.student()) }
+            @if let Some(category) = ticket.category() {
+                p { b { "Category: " } (category) }
+            }
+            p {
+                b { "Description: " }
+                @if let Some(desc) = ticket.desc() {
+                    (desc)
+                } @else {
+                    i { "No description provided" }
+                }
+            }
+            p { b { "Status: " } (ticket.status()) }
+
+            h2 { "History" }
+            div class="terminal-timeline" {
+                @for entry in &entries {
+                    div class="terminal-card history-entry" {
+                        @match entry {
+                            HistoryEntry::Transition(t) => {
+                                header { (t.timestamp.format("%c")) ": " b { (t.status) } " by " (t.actor) }
+                                @if let Some(summary) = &t.summary {
+                                    p { b { "Summary: " } (summary) }
+                                }
+                            }
+                            HistoryEntry::Note(n) => {
+                                header { (n.timestamp.format("%c")) ": " b { "note" } " by " (n.author) }
+                                p class="history-note" { (n.text) }
+                            }
+                        }
+                    }
+                }
+            }
+
+            h2 { "Messages (" (ticket.messages().len()) ")" }
+            (ticket.thread())
+            @if ticket.status().is_open() {
+                form class="t-form" action=(ui::url(format!("/class/{code}/ticket/{}/reply", id.as_usize()))) method="post" {
+                    div class="form-group" {
+                        textarea name="text" rows="2" maxlength=(MAX_MESSAGE_LEN) required
+                            placeholder="Send a message to the student" {}
+                    }
+                    div class="form-group" {
+                        input type="submit" value="Send" class="btn btn-default btn-ghost" {}
+                    }
+                }
+            }
+
+            form class="t-form" action=(ui::url(format!("/class/{code}/ticket/{}/note", id.as_usize()))) method="post" {
+                fieldset {
+                    legend { "Add a note (only visible to staff)" }
+
+                    div class="form-group" {
+                        textarea name="text" rows="3" maxlength=(MAX_NOTE_LEN) required {}
+                    }
+
+                    div class="form-group" {
+                        input type="submit" value="Add note" class="btn btn-default btn-ghost" {}
+                    }
+                }
+            }
+
+            a href=(back) { "Back to the class." }
+        },
+    ))
+}
+
+/// Contains the data submitted when a member of staff adds a note to a ticket
+#[derive(Deserialize)]
+pub struct NoteData {
+    /// The contents of the note
+    text: String,
+}
+
+/// Handler for a member of staff adding a private note to a ticket
+pub async fn add_note(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path((class_id, id)): Path<(String, TicketId)>,
+    headers: HeaderMap,
+    Form(data): Form<NoteData>,
+) -> Result<Redirect, ClassError> {
+    let code = state.lookup_code(ip, &class_id)?;
+
+    // every member of staff may take notes on the tickets they help with
+    let member = auth::require_teacher(&state, code, &headers)?;
+    member.require(Permission::ManageTickets)?;
+
+    if let Err(e) = state.add_note(code, id, Actor::staff(&member), &data.text) {
+        tracing::warn!(%code, member = member.name(), error = %e, "failed to add note");
+    }
+
+    Ok(Redirect::to(&ui::url(format!(
+        "/class/{class_id}/ticket/{}/history",
+        id.as_usize()
+    ))))
+}
+
+/// Contains the data submitted when a member of staff replies in a ticket's thread
+#[derive(Deserialize)]
+pub struct ReplyData {
+    /// The contents of the message
+    text: String,
+}
+
+/// Handler for a member of staff posting a message to a ticket's thread, either from the ticket's history
+/// page or the ticket list (via `teacher-view.js`)
+pub async fn reply(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Path((class_id, id)): Path<(String, TicketId)>,
+    headers: HeaderMap,
+    Form(data): Form<ReplyData>,
+) -> Result<Redirect, ClassError> {
+    let code = state.lookup_code(ip, &class_id)?;
+
+    let member = auth::require_teacher(&state, code, &headers)?;
+    member.require(Permission::ManageTickets)?;
+
+    if let Err(e) = state.post_message(code, id, Actor::staff(&member), &data.text) {
+        tracing::warn!(%code, member = member.name(), error = %e, "failed to post message");
+    }
+
+    Ok(Redirect::to(&ui::url(format!(
+        "/class/{class_id}/ticket/{}/history",
+        id.as_usize()
+    ))))
+}
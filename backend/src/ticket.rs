@@ -6,19 +6,183 @@ use maud::Render;
 
 use chrono::{DateTime, Utc};
 
-use web_push_native::WebPushBuilder;
+use crate::auth::Token;
+use crate::push::{self, Subscriber};
+use crate::queue::Policy;
+use crate::staff::{Staff, StaffList};
+use crate::state::UnknownClass;
 
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::fmt;
 
-/// List of tickets, and IDs of tickets that have been dismissed
+/// Error type for invalid operations on a [`TicketList`]
+#[derive(thiserror::Error, Debug)]
+pub enum TicketError {
+    /// The ticket's class has been closed
+    #[error(transparent)]
+    UnknownClass(#[from] UnknownClass),
+    /// No ticket exists with the given ID
+    #[error("Unknown ticket {0}")]
+    UnknownTicket(TicketId),
+    /// The ticket has been resolved or cancelled, so can no longer be changed
+    #[error("Ticket {0} has already been closed")]
+    Closed(TicketId),
+    /// The ticket can't move from its current status to the requested one
+    #[error("Ticket {id} can't go from {from} to {to}")]
+    InvalidTransition {
+        id: TicketId,
+        from: Status,
+        to: Status,
+    },
+    /// The class has categories, but none was chosen for the ticket
+    #[error("Please choose a category for your ticket")]
+    CategoryRequired,
+    /// The category chosen for the ticket isn't one of the class's categories
+    #[error("Unknown category {0:?}")]
+    UnknownCategory(String),
+    /// The student already has an open ticket, and the class doesn't allow duplicates
+    #[error("You already have an open ticket ({0}), please wait for the teacher to get to it")]
+    Duplicate(TicketId),
+    /// A note was added to a ticket without any text
+    #[error("Notes can't be empty")]
+    EmptyNote,
+    /// A message was posted to a ticket's thread without any text
+    #[error("Messages can't be empty")]
+    EmptyMessage,
+    /// The ticket's thread already has [`MAX_MESSAGES`] messages
+    #[error("Ticket {0} has too many messages, please talk in person instead")]
+    TooManyMessages(TicketId),
+}
+
+/// What happens when a student opens a ticket while they already have one open
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Duplicates {
+    /// The new ticket is refused
+    #[default]
+    Reject,
+    /// The new ticket's description is added to the existing ticket
+    Merge,
+    /// The new ticket is opened anyway, e.g. for classes where students share computers
+    Allow,
+}
+
+impl Duplicates {
+    /// Every option, in the order they are presented to the teacher
+    pub const ALL: [Duplicates; 3] = [Duplicates::Reject, Duplicates::Merge, Duplicates::Allow];
+
+    /// The name of the option, as used in forms and the JSON API
+    pub fn name(&self) -> &'static str {
+        match self {
+            Duplicates::Reject => "reject",
+            Duplicates::Merge => "merge",
+            Duplicates::Allow => "allow",
+        }
+    }
+}
+
+impl fmt::Display for Duplicates {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Duplicates::Reject => "refuse them",
+            Duplicates::Merge => "merge them into the open ticket",
+            Duplicates::Allow => "allow them",
+        };
+
+        write!(fmt, "{name}")
+    }
+}
+
+/// The result of successfully adding a ticket to a [`TicketList`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Added {
+    /// A new ticket was opened
+    New(TicketId),
+    /// The student already had an open ticket, which the new one was merged into. `same_session` is `true`
+    /// if the open ticket was found by the student's session, rather than only by their name
+    Merged { id: TicketId, same_session: bool },
+}
+
+impl Added {
+    /// Returns the ID of the new or merged-into ticket
+    pub fn id(&self) -> TicketId {
+        match self {
+            Added::New(id) | Added::Merged { id, .. } => *id,
+        }
+    }
+}
+
+/// Normalise a student's name, so that the same student is recognised regardless of case or spacing
+pub fn normalise_name(name: &str) -> String {
+    name.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// The most categories a class may have
+pub const MAX_CATEGORIES: usize = 20;
+
+/// The longest a category's name may be, in characters
+pub const MAX_CATEGORY_LEN: usize = 40;
+
+/// The longest a student's name may be, in characters
+pub const MAX_NAME_LEN: usize = 60;
+
+/// The longest a ticket's description may be, in characters, including any merged into it
+pub const MAX_DESC_LEN: usize = 300;
+
+/// The longest a note on a ticket may be, in characters
+pub const MAX_NOTE_LEN: usize = 1000;
+
+/// The longest a ticket's resolution summary may be, in characters
+pub const MAX_SUMMARY_LEN: usize = 200;
+
+/// The longest a message in a ticket's thread may be, in characters
+pub const MAX_MESSAGE_LEN: usize = 500;
+
+/// The most messages a ticket's thread may have
+pub const MAX_MESSAGES: usize = 100;
+
+/// List of tickets in a class
+#[derive(Clone, Serialize, Deserialize)]
 pub struct TicketList {
     /// List of tickets
     tickets: Vec<Ticket>,
-    /// Subscription for push notifications
-    subscriber: Option<WebPushBuilder>,
-    /// A `HashSet` of all the dismissed tickets, for efficient lookup
-    dismissed: HashSet<TicketId>,
+    /// Devices subscribed to push notifications
+    #[serde(
+        default,
+        alias = "subscriber",
+        deserialize_with = "push::deserialize_subscribers"
+    )]
+    subscribers: Vec<Subscriber>,
+    /// Secret token given to the teacher that created the class (its owner)
+    #[serde(default = "Token::generate")]
+    teacher_token: Token,
+    /// The owner's name, and any other staff they have invited
+    #[serde(default)]
+    staff: StaffList,
+    /// Secret tokens given to the student that opened each ticket, required to edit or withdraw it. Kept
+    /// separate from the [`Ticket`]s so they are never exposed alongside the ticket
+    #[serde(default)]
+    student_tokens: HashMap<TicketId, Token>,
+    /// The session of the browser that opened each ticket, used to recognise students opening more than one
+    /// ticket. Kept separate from the [`Ticket`]s for the same reason as `student_tokens`
+    #[serde(default)]
+    student_sessions: HashMap<TicketId, String>,
+    /// The last time anything in the class changed, used to close abandoned classes
+    #[serde(default = "Utc::now")]
+    last_active: DateTime<Utc>,
+    /// Categories defined by the teacher, one of which students must choose when opening a ticket. If
+    /// empty, tickets have no category
+    #[serde(default)]
+    categories: Vec<String>,
+    /// The policy deciding the order tickets are helped in
+    #[serde(default)]
+    policy: Policy,
+    /// What happens when a student opens a ticket while they already have one open
+    #[serde(default)]
+    duplicates: Duplicates,
 }
 
 impl TicketList {
@@ -26,24 +190,187 @@ impl TicketList {
     pub fn new() -> TicketList {
         TicketList {
             tickets: vec![],
-            subscriber: None,
-            dismissed: HashSet::new(),
+            subscribers: vec![],
+            teacher_token: Token::generate(),
+            staff: StaffList::default(),
+            student_tokens: HashMap::new(),
+            student_sessions: HashMap::new(),
+            last_active: Utc::now(),
+            categories: vec![],
+            policy: Policy::default(),
+            duplicates: Duplicates::default(),
+        }
+    }
+
+    /// Returns the last time anything in the class changed
+    pub fn last_active(&self) -> DateTime<Utc> {
+        self.last_active
+    }
+
+    /// Mark the class as active
+    pub fn touch(&mut self) {
+        self.last_active = Utc::now();
+    }
+
+    /// Returns the token required to act as the class's owner
+    pub fn teacher_token(&self) -> &Token {
+        &self.teacher_token
+    }
+
+    /// Returns the class's owner
+    pub fn owner(&self) -> Staff {
+        self.staff.owner(&self.teacher_token)
+    }
+
+    /// Find the member of staff (including the owner) with a given token
+    pub fn member(&self, given: &str) -> Option<Staff> {
+        if self.teacher_token.matches(given) {
+            Some(self.owner())
+        } else {
+            self.staff.find(given).cloned()
         }
     }
 
+    pub fn staff(&self) -> &StaffList {
+        &self.staff
+    }
+
+    pub fn staff_mut(&mut self) -> &mut StaffList {
+        &mut self.staff
+    }
+
+    /// Returns the token required to act as the student that opened a given ticket
+    pub fn student_token(&self, id: TicketId) -> Option<&Token> {
+        self.student_tokens.get(&id)
+    }
+
     pub fn len(&self) -> usize {
         self.tickets.len()
     }
 
-    /// Create a new ticket from a student's name and optional description, and return it's ID
+    /// Returns the categories students choose from when opening a ticket
+    pub fn categories(&self) -> &[String] {
+        &self.categories
+    }
+
+    /// Replace the class's categories. Blank and duplicate names are ignored, long names are shortened,
+    /// and only the first [`MAX_CATEGORIES`] are kept. Existing tickets keep their category.
+    pub fn set_categories<S: AsRef<str>>(&mut self, categories: impl IntoIterator<Item = S>) {
+        self.categories.clear();
+
+        for category in categories {
+            let category: String = category
+                .as_ref()
+                .trim()
+                .chars()
+                .take(MAX_CATEGORY_LEN)
+                .collect();
+
+            if !category.is_empty() && !self.categories.contains(&category) {
+                self.categories.push(category);
+            }
+
+            if self.categories.len() == MAX_CATEGORIES {
+                break;
+            }
+        }
+    }
+
+    /// Returns the policy deciding the order tickets are helped in
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    /// Returns the tickets that are still open, in the order they should be helped in according to the
+    /// class's policy
+    pub fn queue(&self) -> Vec<&Ticket> {
+        let mut open: Vec<_> = self
+            .tickets
+            .iter()
+            .filter(|t| t.status().is_open())
+            .collect();
+        self.policy.implementation().sort(self, &mut open);
+
+        open
+    }
+
+    /// Returns what happens when a student opens a ticket while they already have one open
+    pub fn duplicates(&self) -> Duplicates {
+        self.duplicates
+    }
+
+    pub fn set_duplicates(&mut self, duplicates: Duplicates) {
+        self.duplicates = duplicates;
+    }
+
+    /// Find an open ticket belonging to a student, identified by their session if they have one, and
+    /// otherwise by name. Returns the ticket's ID, and whether it was found by the student's session.
+    pub fn find_open(&self, session: Option<&str>, student: &str) -> Option<(TicketId, bool)> {
+        let mut open = self.tickets.iter().filter(|t| t.status().is_open());
+
+        if let Some(session) = session {
+            let found = open
+                .clone()
+                .find(|t| self.student_sessions.get(&t.id).map(String::as_str) == Some(session));
+
+            if let Some(ticket) = found {
+                return Some((ticket.id, true));
+            }
+        }
+
+        let name = normalise_name(student);
+        open.find(|t| normalise_name(&t.student) == name)
+            .map(|t| (t.id, false))
+    }
+
+    /// Create a new ticket from a student's name, optional description and category. If the class has
+    /// categories, then one of them must be chosen. The student's `session` (if they have one) is used to
+    /// recognise them if they already have a ticket open, in which case the class's [`Duplicates`] setting
+    /// decides what happens.
     pub fn add_ticket(
         &mut self,
         student: impl AsRef<str>,
         desc: Option<impl AsRef<str>>,
-    ) -> TicketId {
-        // get `&str`/`Option<&str>` from student/desc
-        let student = student.as_ref().trim().to_string();
-        let desc = desc.map(|d| d.as_ref().to_string());
+        category: Option<impl AsRef<str>>,
+        session: Option<&str>,
+    ) -> Result<Added, TicketError> {
+        // trim the student's name, and shorten it and the description to their maximum lengths
+        let student: String = student.as_ref().trim().chars().take(MAX_NAME_LEN).collect();
+        let desc = desc.map(|d| d.as_ref().chars().take(MAX_DESC_LEN).collect::<String>());
+
+        // check the category is one of the class's
+        let category = match category.map(|c| c.as_ref().trim().to_string()) {
+            Some(c) if self.categories.contains(&c) => Some(c),
+            Some(c) if !c.is_empty() => return Err(TicketError::UnknownCategory(c)),
+            _ if !self.categories.is_empty() => return Err(TicketError::CategoryRequired),
+            _ => None,
+        };
+
+        match (self.duplicates, self.find_open(session, &student)) {
+            (Duplicates::Reject, Some((id, _))) => return Err(TicketError::Duplicate(id)),
+            (Duplicates::Merge, Some((id, same_session))) => {
+                // add the new description to the end of the existing ticket's
+                if let Some(desc) = desc {
+                    let ticket = &mut self.tickets[id.0];
+
+                    ticket.desc = Some(match ticket.desc.take() {
+                        Some(existing) => format!("{existing}; {desc}")
+                            .chars()
+                            .take(MAX_DESC_LEN)
+                            .collect(),
+                        None => desc,
+                    });
+                    ticket.edited = Some(Utc::now());
+                }
+
+                return Ok(Added::Merged { id, same_session });
+            }
+            _ => (),
+        }
 
         // get the ticket's ID
         let id = TicketId(self.tickets.len());
@@ -57,49 +384,304 @@ impl TicketList {
             student,
             desc,
             timestamp,
+            // every ticket starts out open, as requested by the student
+            history: vec![Transition {
+                status: Status::Open,
+                actor: Actor::Student,
+                timestamp,
+                summary: None,
+            }],
+            edited: None,
+            category,
+            bumped: None,
+            notes: vec![],
+            messages: vec![],
+            staff_read: 0,
         });
 
+        // give the student a token, so that they can change the ticket later
+        self.student_tokens.insert(id, Token::generate());
+
+        if let Some(session) = session {
+            self.student_sessions.insert(id, session.to_string());
+        }
+
         // return new ID
-        id
+        Ok(Added::New(id))
+    }
+
+    /// Iterate over every ticket in the list, oldest first
+    pub fn tickets(&self) -> impl Iterator<Item = &Ticket> + Clone {
+        self.tickets.iter()
+    }
+
+    /// Retrieve a ticket by its ID
+    pub fn get(&self, id: TicketId) -> Option<&Ticket> {
+        self.tickets.get(id.0)
+    }
+
+    /// Returns the position of an open ticket in the queue, where `1` is the next to be helped. Returns
+    /// `None` if the ticket doesn't exist, or is no longer waiting to be picked up.
+    pub fn position(&self, id: TicketId) -> Option<usize> {
+        let ticket = self.get(id)?;
+
+        if ticket.status() != Status::Open {
+            return None;
+        }
+
+        // count the waiting tickets that will be helped before this one
+        let ahead = self
+            .queue()
+            .into_iter()
+            .filter(|t| t.status() == Status::Open)
+            .take_while(|t| t.id != id)
+            .count();
+
+        Some(ahead + 1)
+    }
+
+    /// Estimate how long the ticket at a given queue position will wait before being picked up, based on
+    /// how long the teacher spent on the most recently resolved tickets. Returns `None` if no tickets
+    /// have been resolved yet.
+    pub fn estimate_wait(&self, position: usize) -> Option<chrono::Duration> {
+        /// The number of recently resolved tickets to average over
+        const SAMPLES: usize = 10;
+
+        // collect `(resolved, time spent)` for every resolved ticket
+        let mut resolved: Vec<_> = self
+            .tickets
+            .iter()
+            .filter_map(|t| {
+                let resolved = t.time_of(Status::Resolved)?;
+                Some((resolved, resolved - t.picked_up_at()))
+            })
+            .collect();
+
+        if resolved.is_empty() {
+            return None;
+        }
+
+        // take the most recently resolved tickets
+        resolved.sort_by_key(|(at, _)| std::cmp::Reverse(*at));
+        resolved.truncate(SAMPLES);
+
+        let total = resolved
+            .iter()
+            .fold(chrono::Duration::zero(), |acc, (_, spent)| acc + *spent);
+        let average = total / resolved.len() as i32;
+
+        Some(average * position as i32)
+    }
+
+    /// Replace the description of an open ticket, marking it as edited
+    pub fn edit(&mut self, id: TicketId, desc: Option<impl AsRef<str>>) -> Result<(), TicketError> {
+        let ticket = self
+            .tickets
+            .get_mut(id.0)
+            .ok_or(TicketError::UnknownTicket(id))?;
+
+        if !ticket.status().is_open() {
+            return Err(TicketError::Closed(id));
+        }
+
+        ticket.desc = desc.map(|d| d.as_ref().chars().take(MAX_DESC_LEN).collect());
+        ticket.edited = Some(Utc::now());
+
+        Ok(())
+    }
+
+    /// Bump an open ticket, so that it is helped first when the class uses the priority policy, or remove
+    /// the ticket's bump
+    pub fn bump(&mut self, id: TicketId, bumped: bool) -> Result<(), TicketError> {
+        let ticket = self
+            .tickets
+            .get_mut(id.0)
+            .ok_or(TicketError::UnknownTicket(id))?;
+
+        if !ticket.status().is_open() {
+            return Err(TicketError::Closed(id));
+        }
+
+        ticket.bumped = bumped.then(Utc::now);
+
+        Ok(())
+    }
+
+    /// Move a ticket to a new status, recording who made the change. If the ticket is being resolved or
+    /// cancelled, a short `summary` of what the problem turned out to be may be given.
+    pub fn transition(
+        &mut self,
+        id: TicketId,
+        status: Status,
+        actor: Actor,
+        summary: Option<impl AsRef<str>>,
+    ) -> Result<(), TicketError> {
+        let ticket = self
+            .tickets
+            .get_mut(id.0)
+            .ok_or(TicketError::UnknownTicket(id))?;
+
+        let from = ticket.status();
+
+        if !from.can_become(status) {
+            return Err(TicketError::InvalidTransition {
+                id,
+                from,
+                to: status,
+            });
+        }
+
+        // only closing a ticket can be summarised, and blank summaries are ignored
+        let summary = summary
+            .filter(|_| !status.is_open())
+            .map(|s| {
+                s.as_ref()
+                    .trim()
+                    .chars()
+                    .take(MAX_SUMMARY_LEN)
+                    .collect::<String>()
+            })
+            .filter(|s| !s.is_empty());
+
+        ticket.history.push(Transition {
+            status,
+            actor,
+            timestamp: Utc::now(),
+            summary,
+        });
+
+        Ok(())
+    }
+
+    /// Add a private note to a ticket, which is only shown to the class's staff. Notes can be added to
+    /// closed tickets too, so staff can follow up on them later.
+    pub fn add_note(
+        &mut self,
+        id: TicketId,
+        author: Actor,
+        text: impl AsRef<str>,
+    ) -> Result<(), TicketError> {
+        let ticket = self
+            .tickets
+            .get_mut(id.0)
+            .ok_or(TicketError::UnknownTicket(id))?;
+
+        let text: String = text.as_ref().trim().chars().take(MAX_NOTE_LEN).collect();
+
+        if text.is_empty() {
+            return Err(TicketError::EmptyNote);
+        }
+
+        ticket.notes.push(Note {
+            author,
+            text,
+            timestamp: Utc::now(),
+        });
+
+        Ok(())
+    }
+
+    /// Post a message to an open ticket's thread, which is shown to both the student and the class's staff.
+    /// Messages posted by staff mark the thread as read, as they must have seen it to reply.
+    pub fn post_message(
+        &mut self,
+        id: TicketId,
+        author: Actor,
+        text: impl AsRef<str>,
+    ) -> Result<(), TicketError> {
+        let ticket = self
+            .tickets
+            .get_mut(id.0)
+            .ok_or(TicketError::UnknownTicket(id))?;
+
+        if !ticket.status().is_open() {
+            return Err(TicketError::Closed(id));
+        }
+
+        if ticket.messages.len() >= MAX_MESSAGES {
+            return Err(TicketError::TooManyMessages(id));
+        }
+
+        let text: String = text.as_ref().trim().chars().take(MAX_MESSAGE_LEN).collect();
+
+        if text.is_empty() {
+            return Err(TicketError::EmptyMessage);
+        }
+
+        let from_staff = author != Actor::Student;
+        ticket.messages.push(Message {
+            author,
+            text,
+            timestamp: Utc::now(),
+        });
+
+        if from_staff {
+            ticket.staff_read = ticket.messages.len();
+        }
+
+        Ok(())
     }
 
-    /// Dismiss a given ticket
-    pub fn dismiss(&mut self, id: TicketId) {
-        self.dismissed.insert(id);
+    /// Mark every message in a ticket's thread as read by the class's staff. Returns `false` if there were
+    /// no unread messages.
+    pub fn mark_read(&mut self, id: TicketId) -> Result<bool, TicketError> {
+        let ticket = self
+            .tickets
+            .get_mut(id.0)
+            .ok_or(TicketError::UnknownTicket(id))?;
+
+        let unread = ticket.staff_read != ticket.messages.len();
+        ticket.staff_read = ticket.messages.len();
+
+        Ok(unread)
     }
 
-    /// Subscribe a user to the list
-    pub fn subscribe(&mut self, builder: WebPushBuilder) {
-        if self.subscriber.is_none() {
-            self.subscriber = Some(builder);
+    /// Subscribe a device to the list. If the device is already subscribed, it is replaced
+    pub fn subscribe(&mut self, sub: Subscriber) {
+        self.unsubscribe(sub.endpoint());
+        self.subscribers.push(sub);
+    }
+
+    /// Unsubscribe the device with the given endpoint. Returns `false` if it wasn't subscribed
+    pub fn unsubscribe(&mut self, endpoint: &str) -> bool {
+        let len = self.subscribers.len();
+        self.subscribers.retain(|s| s.endpoint() != endpoint);
+
+        self.subscribers.len() != len
+    }
+
+    /// Record that a notification was delivered to the device with the given endpoint
+    pub fn push_succeeded(&mut self, endpoint: &str) {
+        if let Some(sub) = self
+            .subscribers
+            .iter_mut()
+            .find(|s| s.endpoint() == endpoint)
+        {
+            sub.succeeded();
         }
     }
 
-    pub fn subscriber(&self) -> Option<WebPushBuilder> {
-        self.subscriber.clone()
+    pub fn subscribers(&self) -> &[Subscriber] {
+        &self.subscribers
     }
 }
 
 impl Render for TicketList {
     fn render(&self) -> maud::Markup {
-        let tickets = self
-            .tickets
-            .iter()
-            // filter out tickets that have been dismissed
-            .filter(|t| !self.dismissed.contains(&t.id));
+        // resolved and cancelled tickets are left out of the queue
+        let tickets = self.queue();
 
-        // if the length of dismissed == length of ticket list, then all tickets have been dismissed
-        let is_empty = self.tickets.len() == self.dismissed.len();
+        // if there are no open tickets, display a message instead
+        let is_empty = tickets.is_empty();
 
         maud::html! {
-            @if is_empty {
-                i { "No tickets open" }
-            } @ else {
-                div class="terminal-timeline" {
-                    // render all tickets
-                    @for ticket in tickets {
-                        (ticket)
-                    }
+            // message is hidden rather than omitted, so that it can be toggled as tickets are added/removed
+            i class="no-tickets" hidden[!is_empty] { "No tickets open" }
+
+            div class="terminal-timeline" {
+                // render all tickets
+                @for ticket in tickets {
+                    (ticket)
                 }
             }
         }
@@ -107,15 +689,136 @@ impl Render for TicketList {
 }
 
 /// Ticket ID. Newtype ensures anywhere we ask for a `TicketId`, it is valid
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct TicketId(usize);
 
+impl TicketId {
+    /// Return the inner `usize` contained in the ticket ID
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
+}
+
 impl fmt::Display for TicketId {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         write!(fmt, "#{}", self.0)
     }
 }
 
+/// The stage of its lifecycle a ticket is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// Waiting for a teacher to pick up the ticket
+    Open,
+    /// A teacher has said they will deal with the ticket
+    Claimed,
+    /// A teacher is currently helping the student
+    InProgress,
+    /// The student has been helped
+    Resolved,
+    /// The ticket was closed without the student being helped
+    Cancelled,
+}
+
+impl Status {
+    /// Returns `true` if the ticket still needs dealing with
+    pub fn is_open(&self) -> bool {
+        matches!(self, Status::Open | Status::Claimed | Status::InProgress)
+    }
+
+    /// Returns `true` if a ticket in this status is allowed to move to the `next` status
+    pub fn can_become(&self, next: Status) -> bool {
+        use Status::*;
+
+        matches!(
+            (self, next),
+            (Open, Claimed | InProgress | Resolved | Cancelled)
+                | (Claimed, InProgress | Resolved | Cancelled)
+                | (InProgress, Resolved | Cancelled)
+        )
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Status::Open => "open",
+            Status::Claimed => "claimed",
+            Status::InProgress => "in progress",
+            Status::Resolved => "resolved",
+            Status::Cancelled => "cancelled",
+        };
+
+        write!(fmt, "{name}")
+    }
+}
+
+/// The user responsible for a change to a ticket
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Actor {
+    /// The student that opened the ticket
+    Student,
+    /// The class's teacher, for changes made before classes could have multiple staff
+    Teacher,
+    /// A member of the class's staff, by display name
+    Staff(String),
+}
+
+impl Actor {
+    /// The actor for a change made by a given member of staff
+    pub fn staff(member: &Staff) -> Actor {
+        Actor::Staff(member.name().to_string())
+    }
+}
+
+impl fmt::Display for Actor {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Actor::Student => write!(fmt, "the student"),
+            Actor::Teacher => write!(fmt, "the teacher"),
+            Actor::Staff(name) => write!(fmt, "{name}"),
+        }
+    }
+}
+
+/// A change in a ticket's status
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Transition {
+    /// The status the ticket moved to
+    pub status: Status,
+    /// Who made the change
+    pub actor: Actor,
+    /// When the change was made
+    pub timestamp: DateTime<Utc>,
+    /// What the problem turned out to be, given by the member of staff that resolved or cancelled the ticket
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+}
+
+/// A message in a ticket's thread, from either the student or a member of staff
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message {
+    /// Who posted the message
+    pub author: Actor,
+    /// The contents of the message
+    pub text: String,
+    /// When the message was posted
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A private note left on a ticket by a member of staff
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Note {
+    /// Who wrote the note
+    pub author: Actor,
+    /// The contents of the note
+    pub text: String,
+    /// When the note was written
+    pub timestamp: DateTime<Utc>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Ticket {
     /// ID of the ticket
@@ -126,6 +829,161 @@ pub struct Ticket {
     desc: Option<String>,
     /// The timestamp the ticket was created at
     timestamp: DateTime<Utc>,
+    /// Every change in the ticket's status, oldest first
+    #[serde(default)]
+    history: Vec<Transition>,
+    /// The last time the student edited the ticket, if they have
+    #[serde(default)]
+    edited: Option<DateTime<Utc>>,
+    /// The category chosen by the student, if the class has categories
+    #[serde(default)]
+    category: Option<String>,
+    /// When the teacher bumped the ticket up the queue, if they have
+    #[serde(default)]
+    bumped: Option<DateTime<Utc>>,
+    /// Private notes left by the class's staff, oldest first
+    #[serde(default)]
+    notes: Vec<Note>,
+    /// Messages between the student and the class's staff, oldest first
+    #[serde(default)]
+    messages: Vec<Message>,
+    /// The number of messages in the thread that the class's staff have read
+    #[serde(default)]
+    staff_read: usize,
+}
+
+impl Ticket {
+    /// Returns the ID of the ticket
+    pub fn id(&self) -> TicketId {
+        self.id
+    }
+
+    /// Returns the name of the student that opened the ticket
+    pub fn student(&self) -> &str {
+        &self.student
+    }
+
+    /// Returns the description of the ticket, if one was given
+    pub fn desc(&self) -> Option<&str> {
+        self.desc.as_deref()
+    }
+
+    /// Returns the time the ticket was opened
+    pub fn created(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    /// Returns when the ticket was bumped up the queue, if it has been
+    pub fn bumped(&self) -> Option<DateTime<Utc>> {
+        self.bumped
+    }
+
+    /// Returns the category of the ticket, if it has one
+    pub fn category(&self) -> Option<&str> {
+        self.category.as_deref()
+    }
+
+    /// Returns every change in the ticket's status, oldest first
+    pub fn history(&self) -> &[Transition] {
+        &self.history
+    }
+
+    /// Returns the private notes left by the class's staff, oldest first
+    pub fn notes(&self) -> &[Note] {
+        &self.notes
+    }
+
+    /// Returns the messages between the student and the class's staff, oldest first
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    /// Returns the number of messages from the student that the class's staff haven't read yet
+    pub fn unread(&self) -> usize {
+        self.messages
+            .iter()
+            .skip(self.staff_read)
+            .filter(|m| m.author == Actor::Student)
+            .count()
+    }
+
+    /// The name shown for the author of a message, using the student's name rather than "the student"
+    fn author_name(&self, author: &Actor) -> String {
+        match author {
+            Actor::Student => self.student.clone(),
+            author => author.to_string(),
+        }
+    }
+
+    /// Renders the ticket's thread of messages, as shown to both the student and the class's staff
+    pub fn thread(&self) -> maud::Markup {
+        maud::html! {
+            @if self.messages.is_empty() {
+                p { i { "No messages yet." } }
+            }
+            @for message in &self.messages {
+                @let class = match message.author {
+                    Actor::Student => "thread-message thread-message-student",
+                    _ => "thread-message thread-message-staff",
+                };
+
+                div class=(class) {
+                    header {
+                        b { (self.author_name(&message.author)) } " at " (message.timestamp.format("%X"))
+                    }
+                    p { (message.text) }
+                }
+            }
+        }
+    }
+
+    /// Returns the summary given when the ticket was closed, if it has been closed with one
+    pub fn summary(&self) -> Option<&str> {
+        self.last_transition()
+            .filter(|t| !t.status.is_open())
+            .and_then(|t| t.summary.as_deref())
+    }
+
+    /// Returns a copy of the ticket without anything private to the class's staff, so that it can be shown
+    /// to students
+    pub fn for_student(&self) -> Ticket {
+        let mut ticket = self.clone();
+        ticket.notes.clear();
+        ticket.history.iter_mut().for_each(|t| t.summary = None);
+
+        ticket
+    }
+
+    /// Returns the current status of the ticket
+    pub fn status(&self) -> Status {
+        self.history
+            .last()
+            .map(|t| t.status)
+            .unwrap_or(Status::Open)
+    }
+
+    /// Returns the most recent change to the ticket's status
+    pub fn last_transition(&self) -> Option<&Transition> {
+        self.history.last()
+    }
+
+    /// Returns the time the ticket first entered a given status, if it ever has
+    pub fn time_of(&self, status: Status) -> Option<DateTime<Utc>> {
+        self.history
+            .iter()
+            .find(|t| t.status == status)
+            .map(|t| t.timestamp)
+    }
+
+    /// Returns the time a teacher started dealing with the ticket. If it was never claimed or started, this
+    /// is the time it was created.
+    pub fn picked_up_at(&self) -> DateTime<Utc> {
+        self.history
+            .iter()
+            .find(|t| matches!(t.status, Status::Claimed | Status::InProgress))
+            .map(|t| t.timestamp)
+            .unwrap_or(self.timestamp)
+    }
 }
 
 fn print_elapsed(duration: &chrono::Duration) -> String {
@@ -137,14 +995,56 @@ fn print_elapsed(duration: &chrono::Duration) -> String {
 
 impl Render for Ticket {
     fn render(&self) -> maud::Markup {
-        // action to be called when button clicked, dismisses ticket of given ID
-        let action = format!("update_list({})", self.id.0);
+        // actions to be called when buttons clicked, update the status of ticket of given ID
+        let action = |action: &str| format!("perform('{action}', {})", self.id.0);
+        let close = |action: &str| format!("close_ticket('{action}', {})", self.id.0);
+        let status = self.status();
+
+        // claimed tickets are styled differently, so teachers can see at a glance what's being dealt with
+        let class = match status {
+            Status::Open => "help-card terminal-card",
+            _ => "help-card help-card-claimed terminal-card",
+        };
 
         maud::html! {
-            div class="help-card terminal-card" {
+            // the ID and creation time are used by `teacher-view.js` to update the card in-place, and the
+            // category to filter the list
+            div class=(class)
+                id=(format!("ticket-{}", self.id.0))
+                data-created=(self.timestamp.to_rfc3339())
+                data-category=[self.category()] {
                 @let duration = Utc::now() - self.timestamp;
 
-                header { b { (&self.student) } ", " (print_elapsed(&duration)) " [" (self.id)  "]" }
+                header {
+                    b { (&self.student) } ", "
+                    span class="elapsed" { (print_elapsed(&duration)) }
+                    " [" (self.id)  "]"
+                    @if let Some(category) = self.category() {
+                        " " span class="help-card-category" { (category) }
+                    }
+                    @if self.bumped.is_some() {
+                        " " b class="help-card-bumped" { "(bumped)" }
+                    }
+                    @if let Some(edited) = self.edited {
+                        " " i title=(edited.format("%c")) { "(edited)" }
+                    }
+                    @let unread = self.unread();
+                    @if unread > 0 {
+                        " " b class="help-card-unread" { "(" (unread) " unread)" }
+                    }
+                    " " a class="help-card-history" href=(format!("ticket/{}/history", self.id.0)) {
+                        "History"
+                        @if !self.notes.is_empty() {
+                            " (" (self.notes.len()) " notes)"
+                        }
+                    }
+                }
+
+                @if let (Status::Claimed | Status::InProgress, Some(t)) = (status, self.last_transition()) {
+                    p class="help-card-status" {
+                        b { (status) } " by " (t.actor) " at " (t.timestamp.format("%X"))
+                    }
+                }
 
                 p class="help-card-text" {
                     b { "Created: " }
@@ -158,8 +1058,137 @@ impl Render for Ticket {
                     }
                 }
 
-                button class="btn help-card-btn" onclick=(action) { "[x]" }
+                @if let Some(message) = self.messages.last() {
+                    p class="help-card-message" {
+                        b { (self.author_name(&message.author)) ": " } (message.text)
+                    }
+                }
+
+                div class="help-card-btns" {
+                    button class="btn btn-ghost" onclick=(format!("send_message({})", self.id.0)) { "Message" }
+                    @if status == Status::Open {
+                        button class="btn btn-ghost" onclick=(action("claim")) { "Claim" }
+                    }
+                    @if status == Status::Claimed {
+                        button class="btn btn-ghost" onclick=(action("start")) { "Start" }
+                    }
+                    // bumps only affect the priority policy, so the buttons are hidden by CSS otherwise
+                    @if self.bumped.is_some() {
+                        button class="btn btn-ghost bump-btn" onclick=(action("unbump")) { "Unbump" }
+                    } @else {
+                        button class="btn btn-ghost bump-btn" onclick=(action("bump")) { "Bump" }
+                    }
+                    // closing a ticket asks for a summary of what the problem was
+                    button class="btn btn-ghost" onclick=(close("resolve")) { "Resolve" }
+                    button class="btn btn-ghost" onclick=(close("dismiss")) { "[x]" }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptions_are_truncated() {
+        let long = "x".repeat(MAX_DESC_LEN + 10);
+        let desc_len =
+            |list: &TicketList, id| list.get(id).unwrap().desc().unwrap().chars().count();
+
+        let mut list = TicketList::new();
+        let id = list
+            .add_ticket("Alice", Some(&long), None::<&str>, None)
+            .unwrap()
+            .id();
+        assert_eq!(desc_len(&list, id), MAX_DESC_LEN);
+
+        list.edit(id, Some("short")).unwrap();
+        list.edit(id, Some(&long)).unwrap();
+        assert_eq!(desc_len(&list, id), MAX_DESC_LEN);
+
+        // merging keeps the existing description, and truncates what is added to it
+        list.set_duplicates(Duplicates::Merge);
+        list.edit(id, Some("short")).unwrap();
+        let merged = list
+            .add_ticket("Alice", Some(&long), None::<&str>, None)
+            .unwrap();
+        assert_eq!(merged.id(), id);
+        assert!(list
+            .get(id)
+            .unwrap()
+            .desc()
+            .unwrap()
+            .starts_with("short; x"));
+        assert_eq!(desc_len(&list, id), MAX_DESC_LEN);
+    }
+
+    #[test]
+    fn names_are_truncated() {
+        let long = format!(" {} ", "x".repeat(MAX_NAME_LEN + 10));
+
+        let mut list = TicketList::new();
+        let id = list
+            .add_ticket(&long, None::<&str>, None::<&str>, None)
+            .unwrap()
+            .id();
+
+        assert_eq!(list.get(id).unwrap().student(), "x".repeat(MAX_NAME_LEN));
+    }
+
+    #[test]
+    fn status_transitions() {
+        use Status::*;
+
+        let all = [Open, Claimed, InProgress, Resolved, Cancelled];
+        let allowed = [
+            (Open, Claimed),
+            (Open, InProgress),
+            (Open, Resolved),
+            (Open, Cancelled),
+            (Claimed, InProgress),
+            (Claimed, Resolved),
+            (Claimed, Cancelled),
+            (InProgress, Resolved),
+            (InProgress, Cancelled),
+        ];
+
+        for from in all {
+            for to in all {
+                assert_eq!(
+                    from.can_become(to),
+                    allowed.contains(&(from, to)),
+                    "{from:?} -> {to:?}"
+                );
             }
         }
     }
+
+    #[test]
+    fn open_tickets_are_found_by_session_then_name() {
+        let mut list = TicketList::new();
+        let alice = list
+            .add_ticket("Alice", None::<&str>, None::<&str>, Some("s1"))
+            .unwrap()
+            .id();
+        let bob = list
+            .add_ticket("Bob  Smith", None::<&str>, None::<&str>, None)
+            .unwrap()
+            .id();
+
+        // a matching session is preferred, even when the name differs
+        assert_eq!(list.find_open(Some("s1"), "Bob Smith"), Some((alice, true)));
+        assert_eq!(
+            list.find_open(Some("s2"), " bob SMITH "),
+            Some((bob, false))
+        );
+        assert_eq!(list.find_open(None, "alice"), Some((alice, false)));
+        assert_eq!(list.find_open(Some("s2"), "Carol"), None);
+
+        // closed tickets are ignored
+        list.transition(alice, Status::Cancelled, Actor::Student, None::<&str>)
+            .unwrap();
+        assert_eq!(list.find_open(Some("s1"), "Alice"), None);
+    }
 }
@@ -0,0 +1,86 @@
+//! This module contains the logic for serving the application over HTTPS. The only endpoint is the fallback
+//! of the redirect listener, which sends every plain HTTP request to the same path over HTTPS.
+//!
+//! Web Push and service workers are only available in a secure context, so a classroom machine can serve
+//! the application directly rather than behind a reverse proxy. Certificates are loaded with rustls via
+//! [`RustlsConfig`], and reloaded whenever their files change, so renewed certificates (e.g. from Let's
+//! Encrypt) are picked up without restarting the server and closing every class's event streams.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+
+use axum::extract::Host;
+use axum::http::uri::{Authority, Uri};
+use axum::response::Redirect;
+use axum::Router;
+
+use axum_server::tls_rustls::RustlsConfig;
+
+/// How often the certificate files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Load the certificate chain and private key from PEM files
+pub async fn load(cert: &Path, key: &Path) -> anyhow::Result<RustlsConfig> {
+    let config = RustlsConfig::from_pem_file(cert, key)
+        .await
+        .with_context(|| format!("failed to load TLS certificate {}", cert.display()))?;
+    tracing::info!(cert = %cert.display(), "loaded TLS certificate");
+
+    Ok(config)
+}
+
+/// Returns the time the certificate or key was last modified, whichever is later
+fn modified(cert: &Path, key: &Path) -> Option<SystemTime> {
+    let cert = std::fs::metadata(cert).and_then(|m| m.modified()).ok()?;
+    let key = std::fs::metadata(key).and_then(|m| m.modified()).ok()?;
+
+    Some(cert.max(key))
+}
+
+/// Periodically reload the certificate and key when either file changes. If the new files are invalid (e.g.
+/// only one has been replaced so far), the previous certificate is kept and the files are tried again later.
+pub fn watch(config: RustlsConfig, cert: PathBuf, key: PathBuf) {
+    tokio::spawn(async move {
+        let mut loaded = modified(&cert, &key);
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let current = modified(&cert, &key);
+            if current.is_none() || current == loaded {
+                continue;
+            }
+
+            match config.reload_from_pem_file(&cert, &key).await {
+                Ok(()) => {
+                    tracing::info!(cert = %cert.display(), "reloaded TLS certificate");
+                    loaded = current;
+                }
+                Err(e) => {
+                    tracing::warn!(cert = %cert.display(), "failed to reload TLS certificate: {e}")
+                }
+            }
+        }
+    });
+}
+
+/// Returns an app that redirects every request to the same host and path over HTTPS, on the given port
+pub fn redirect(https_port: u16) -> Router {
+    Router::new().fallback(move |Host(host): Host, uri: Uri| async move {
+        // remove the plain HTTP port from the host, keeping the brackets around IPv6 addresses
+        let host = host
+            .parse::<Authority>()
+            .map(|a| a.host().to_string())
+            .unwrap_or(host);
+        let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+
+        if https_port == 443 {
+            Redirect::permanent(&format!("https://{host}{path}"))
+        } else {
+            Redirect::permanent(&format!("https://{host}:{https_port}{path}"))
+        }
+    })
+}
@@ -1,7 +1,32 @@
 //! This module defines the basic components for the UI that the rest of the application relies upon.
 
+use std::sync::OnceLock;
+
 use maud::{Render, DOCTYPE};
 
+use crate::assets;
+
+/// The path the application is mounted at (e.g. `/summoner`), with no trailing slash. Empty when the
+/// application is served from the root.
+static BASE_PATH: OnceLock<String> = OnceLock::new();
+
+/// Set the path the application is mounted at. Must be called before any pages are rendered, and has no
+/// effect if called again.
+pub fn set_base_path(path: String) {
+    let _ = BASE_PATH.set(path);
+}
+
+/// Returns the path the application is mounted at
+pub fn base_path() -> &'static str {
+    BASE_PATH.get().map(String::as_str).unwrap_or("")
+}
+
+/// Prefix a path from the root of the application (e.g. `/class/ABC123/teacher`) with the base path, for
+/// use in links, redirects and cookies
+pub fn url(path: impl AsRef<str>) -> String {
+    format!("{}{}", base_path(), path.as_ref())
+}
+
 /// Creates a link as part of a list
 fn list_link(text: &str, url: &str) -> maud::Markup {
     maud::html! {
@@ -11,20 +36,21 @@ fn list_link(text: &str, url: &str) -> maud::Markup {
     }
 }
 
-/// Renders a sidebar, with a navbar based on a list of `(text, url)`.
+/// Renders a sidebar, with a navbar based on a list of `(text, path)`. Paths are from the root of the
+/// application, see [`url`].
 pub fn sidebar<'a>(items: impl IntoIterator<Item = (&'a str, &'a str)>) -> maud::Markup {
     maud::html! {
         div class="sidebar" {
             header class="terminal-logo" {
                 h1 class="terminal-prompt" {
-                    a href="/" { "Teacher Summoner" }
+                    a href=(url("/")) { "Teacher Summoner" }
                 }
             }
             ul {
                 // for each item in navbar, render list item containing a link
                 @for item in items {
                     li {
-                        a href=(item.1) { (item.0) }
+                        a href=(url(item.1)) { (item.0) }
                     }
                 }
             }
@@ -55,8 +81,8 @@ pub fn base(title: &str, body: impl Render) -> maud::Markup {
             title { (title) " - Teacher Summoner" }
 
             // include stylesheets
-            link rel="stylesheet" href="/static/terminal.min.css" {}
-            link rel="stylesheet" href="/static/style.css" {}
+            link rel="stylesheet" href=(url(assets::path("terminal.min.css"))) {}
+            link rel="stylesheet" href=(url(assets::path("style.css"))) {}
         }
 
         body class="terminal" {
@@ -0,0 +1,64 @@
+//! This module handles the server's VAPID key pair, used to sign push notifications. No endpoints are
+//! defined in this module.
+//!
+//! Push subscriptions are tied to the public key they were created with, so the key pair is stored on
+//! disk and reused between runs. Keys may be stored in either PEM or DER format; newly generated keys
+//! are written as PEM.
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Context;
+
+use base64ct::{Base64UrlUnpadded, Encoding};
+
+use web_push_native::jwt_simple::algorithms::{ECDSAP256KeyPairLike, ES256KeyPair};
+
+/// Load the key pair stored at a given path. If the file does not exist, generate a new key pair and
+/// write it there.
+pub fn load_or_create(path: impl AsRef<Path>) -> anyhow::Result<ES256KeyPair> {
+    let path = path.as_ref();
+
+    if path.exists() {
+        let data = fs::read(path)
+            .with_context(|| format!("failed to read VAPID key from {}", path.display()))?;
+
+        // PEM files are text beginning with a `-----BEGIN` header; anything else is treated as DER
+        let key = if data.starts_with(b"-----BEGIN") {
+            ES256KeyPair::from_pem(std::str::from_utf8(&data)?)
+        } else {
+            ES256KeyPair::from_der(&data)
+        };
+
+        let key = key.with_context(|| format!("invalid VAPID key in {}", path.display()))?;
+        tracing::info!(path = %path.display(), "loaded VAPID key");
+
+        Ok(key)
+    } else {
+        let key = ES256KeyPair::generate();
+
+        write_private(path, key.to_pem()?.as_bytes())
+            .with_context(|| format!("failed to write VAPID key to {}", path.display()))?;
+        tracing::info!(path = %path.display(), "generated new VAPID key");
+
+        Ok(key)
+    }
+}
+
+/// Write a private key to a new file, which on unix is only readable by the current user. Fails if the file
+/// already exists, rather than overwriting a key that may still be in use.
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)?.write_all(data)
+}
+
+/// Encode the public half of a key pair in the URL-safe base64 form expected by browsers
+pub fn public_key(key: &ES256KeyPair) -> String {
+    Base64UrlUnpadded::encode_string(&key.key_pair().public_key().to_bytes_uncompressed())
+}
@@ -1,16 +1,15 @@
 "use strict";
 
-// extract `class_id` from script tag
-let class_id = parseInt(document.currentScript.getAttribute("classid"));
+// extract `class_id`, and the path the app is mounted at, from script tag
+let class_id = document.currentScript.getAttribute("classid");
+let base_path = document.currentScript.getAttribute("basepath") || "";
 console.log("class id = " + class_id);
 
-// updates the list of tickets, optionally dismissing a given ticket
-function update_list(dismissed) {
-    // if dismissed isn't undefined, then we need to dismiss a ticket too
-    let dismissal = dismissed !== undefined ? `&dismissed=${dismissed}` : "";
+// updates the list of tickets
+function update_list() {
     // url to retrieve
-    let path = `/class/${class_id}/teacher?raw=true${dismissal}`;
-    
+    let path = `${base_path}/class/${class_id}/teacher?raw=true`;
+
     // create XHTTP request
     const xhttp = new XMLHttpRequest();
 
@@ -20,6 +19,7 @@ function update_list(dismissed) {
         if (this.readyState == 4 && this.status == 200) {
             // replace list on page with list from server
             document.getElementById("ticket-list").innerHTML = this.responseText;
+            apply_filter();
         }
     };
 
@@ -28,13 +28,177 @@ function update_list(dismissed) {
     xhttp.send();
 }
 
+// perform an action (claim, start, resolve, dismiss, bump) on a ticket, then update the list
+async function perform(action, ticket, summary) {
+    let data = new URLSearchParams();
+    if (summary !== undefined) {
+        data.set("summary", summary);
+    }
+
+    await fetch(`${base_path}/class/${class_id}/ticket/${ticket}/${action}`, {
+        method: "POST",
+        body: data
+    });
+
+    update_list();
+}
+
+// resolve or dismiss a ticket, asking the teacher for a summary of what the problem turned out to be
+function close_ticket(action, ticket) {
+    let summary = prompt("What was the problem? (optional, only visible to staff)");
+
+    // the teacher changed their mind
+    if (summary === null) {
+        return;
+    }
+
+    perform(action, ticket, summary);
+}
+
+// send a message to the student that opened a ticket, which will appear on their status page
+function send_message(ticket) {
+    let text = prompt("Message to the student:");
+
+    if (text === null || text.trim() === "") {
+        return;
+    }
+
+    // the updated ticket is delivered via the event stream
+    fetch(`${base_path}/class/${class_id}/ticket/${ticket}/reply`, {
+        method: "POST",
+        body: new URLSearchParams({ text: text })
+    });
+}
+
 // refresh list every 2.5 seconds
 function refresh() {
     update_list();
     setTimeout(() => { refresh() }, 2500);
 }
 
-refresh();
+// show the "No tickets open" message if there are no tickets in the list
+function update_empty() {
+    let list = document.getElementById("ticket-list");
+    let message = list.querySelector(".no-tickets");
+
+    if (message) {
+        message.hidden = list.querySelector(".help-card") !== null;
+    }
+}
+
+// add a rendered ticket to the end of the list
+function add_ticket(html) {
+    let timeline = document.querySelector("#ticket-list .terminal-timeline");
+
+    if (timeline) {
+        timeline.insertAdjacentHTML("beforeend", html);
+        update_empty();
+        apply_filter();
+    }
+}
+
+// replace a ticket in the list with a newly rendered version
+function replace_ticket(html) {
+    let template = document.createElement("template");
+    template.innerHTML = html.trim();
+
+    let updated = template.content.firstChild;
+    let ticket = document.getElementById(updated.id);
+
+    if (ticket) {
+        ticket.replaceWith(updated);
+        update_elapsed();
+        apply_filter();
+    }
+}
+
+// remove a ticket from the list, if it is present
+function remove_ticket(id) {
+    let ticket = document.getElementById(`ticket-${id}`);
+
+    if (ticket) {
+        ticket.remove();
+        update_empty();
+    }
+}
+
+// update the "time since created" of each ticket, as the server no longer re-renders them
+function update_elapsed() {
+    for (const ticket of document.querySelectorAll("#ticket-list .help-card")) {
+        let created = new Date(ticket.dataset.created);
+        let secs = Math.max(0, Math.floor((Date.now() - created) / 1000));
+        let elapsed = `${Math.floor(secs / 60)}:${String(secs % 60).padStart(2, "0")} ago`;
+
+        ticket.querySelector(".elapsed").textContent = elapsed;
+    }
+}
+
+// the category of tickets to show, or "" to show every ticket
+let category_filter = "";
+
+// hide any tickets that aren't in the chosen category
+function apply_filter() {
+    for (const ticket of document.querySelectorAll("#ticket-list .help-card")) {
+        ticket.hidden = category_filter !== "" && ticket.dataset.category !== category_filter;
+    }
+}
+
+// highlight the chosen category's chip, and filter the list to match
+function choose_category(category) {
+    category_filter = category;
+
+    for (const chip of document.querySelectorAll("#category-filter .category-chip")) {
+        let chosen = chip.dataset.category === category;
+        chip.classList.toggle("btn-primary", chosen);
+        chip.classList.toggle("btn-default", !chosen);
+    }
+
+    apply_filter();
+}
+
+for (const chip of document.querySelectorAll("#category-filter .category-chip")) {
+    chip.addEventListener("click", () => choose_category(chip.dataset.category));
+}
+
+// listen for live updates from the server, falling back to polling if that isn't possible
+function listen() {
+    if (!("EventSource" in window)) {
+        console.log("server-sent events unsupported, polling instead");
+        refresh();
+        return;
+    }
+
+    const events = new EventSource(`${base_path}/class/${class_id}/events`);
+    let connected = false;
+
+    // fetch the whole list whenever we (re)connect, as we may have missed events
+    events.onopen = () => {
+        connected = true;
+        update_list();
+    };
+
+    events.onerror = () => {
+        // if we never managed to connect, the server doesn't support the stream; poll instead
+        if (!connected) {
+            console.log("failed to connect to event stream, polling instead");
+            events.close();
+            refresh();
+        }
+    };
+
+    events.addEventListener("ticket-added", (e) => add_ticket(e.data));
+    events.addEventListener("ticket-updated", (e) => replace_ticket(e.data));
+    events.addEventListener("ticket-dismissed", (e) => remove_ticket(e.data));
+    events.addEventListener("resync", () => update_list());
+    events.addEventListener("class-closed", () => {
+        events.close();
+        document.getElementById("ticket-list").innerHTML = "<i>This class has been closed</i>";
+    });
+
+    setInterval(update_elapsed, 1000);
+}
+
+listen();
 
 // code for subscribing for push notifications
 
@@ -53,15 +217,34 @@ function base64UrlDecode(base64String) {
 }
 
 async function fetchVapidKey() {
-  return fetch("/api/vapid.json").then((resp) => resp.json());
+  return fetch(`${base_path}/api/vapid.json`).then((resp) => resp.json());
 }
 
-async function subToPush(keys) {
-    const registration = await navigator.serviceWorker.register("/static/service-worker.js");
+async function getRegistration() {
+    const registration = await navigator.serviceWorker.register(`${base_path}/static/service-worker.js`);
     await registration.update();
 
-    await registration.pushManager.getSubscription()
-        .then((sub) => sub.unsubscribe());
+    return registration;
+}
+
+// remove this device's subscription from the class, and from the browser
+async function removeSub(sub) {
+    await fetch(`${base_path}/class/${class_id}/unsubscribe`, {
+        method: "POST",
+        body: new URLSearchParams({ endpoint: sub.endpoint })
+    });
+
+    await sub.unsubscribe();
+}
+
+async function subToPush(keys) {
+    const registration = await getRegistration();
+
+    // replace any existing subscription, so the class doesn't keep a stale copy of it
+    const oldSub = await registration.pushManager.getSubscription();
+    if (oldSub) {
+        await removeSub(oldSub);
+    }
 
     const pushSub = await registration.pushManager.subscribe({
         userVisibleOnly: true,
@@ -109,14 +292,30 @@ async function subscribe() {
         await requestPermission();
 
         let sub = await subToPush(keys);
+        let label = prompt("Name this device, so you can tell it apart from your others:", navigator.platform);
 
-        await fetch(`/class/${class_id}/register`, {
+        await fetch(`${base_path}/class/${class_id}/register`, {
             method: "POST",
             headers: {
                 "Content-Type": "application/json"
             },
-            body: JSON.stringify(sub)
+            body: JSON.stringify({ subscription: sub, label: label })
         });
+
+        location.reload();
+    }
+}
+
+async function unsubscribe() {
+    if (browserSupported()) {
+        const registration = await getRegistration();
+        const sub = await registration.pushManager.getSubscription();
+
+        if (sub) {
+            await removeSub(sub);
+        }
+
+        location.reload();
     }
 }
 
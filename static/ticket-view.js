@@ -0,0 +1,89 @@
+"use strict";
+
+// extract `class_id`, `ticket_id` and the path the app is mounted at from script tag
+let class_id = document.currentScript.getAttribute("classid");
+let ticket_id = parseInt(document.currentScript.getAttribute("ticketid"));
+let base_path = document.currentScript.getAttribute("basepath") || "";
+console.log(`class id = ${class_id}, ticket id = ${ticket_id}`);
+
+// base url for the ticket's status
+let path = `${base_path}/class/${class_id}/ticket/${ticket_id}`;
+
+// replace the status shown on the page
+function set_status(html) {
+    document.getElementById("ticket-status").innerHTML = html;
+}
+
+// fetches the ticket's status from the server
+function update_status() {
+    fetch(`${path}?raw=true`)
+        .then((resp) => resp.ok ? resp.text() : Promise.reject(resp.status))
+        .then(set_status)
+        .catch((error) => console.log("failed to fetch status: " + error));
+}
+
+// replace the thread of messages shown on the page, which is only present for the student that opened
+// the ticket
+function set_thread(html) {
+    let thread = document.getElementById("ticket-thread");
+
+    if (thread) {
+        thread.innerHTML = html;
+    }
+}
+
+// fetches the ticket's thread from the server
+function update_thread() {
+    if (!document.getElementById("ticket-thread")) {
+        return;
+    }
+
+    fetch(`${path}?raw=true&thread=true`)
+        .then((resp) => resp.ok ? resp.text() : Promise.reject(resp.status))
+        .then(set_thread)
+        .catch((error) => console.log("failed to fetch messages: " + error));
+}
+
+// refresh status every 5 seconds
+function refresh() {
+    update_status();
+    update_thread();
+    setTimeout(() => { refresh() }, 5000);
+}
+
+// listen for live updates from the server, falling back to polling if that isn't possible
+function listen() {
+    if (!("EventSource" in window)) {
+        console.log("server-sent events unsupported, polling instead");
+        refresh();
+        return;
+    }
+
+    const events = new EventSource(`${path}/events`);
+    let connected = false;
+
+    // fetch the status whenever we (re)connect, as we may have missed events
+    events.onopen = () => {
+        connected = true;
+        update_status();
+        update_thread();
+    };
+
+    events.onerror = () => {
+        // if we never managed to connect, the server doesn't support the stream; poll instead
+        if (!connected) {
+            console.log("failed to connect to event stream, polling instead");
+            events.close();
+            refresh();
+        }
+    };
+
+    events.addEventListener("status", (e) => set_status(e.data));
+    events.addEventListener("messages", (e) => set_thread(e.data));
+    events.addEventListener("class-closed", () => {
+        events.close();
+        set_status("<i>This class has been closed</i>");
+    });
+}
+
+listen();